@@ -0,0 +1,53 @@
+/*
+ * C interface to the Rust xxhash crate, built by the xxhash-capi crate.
+ *
+ * The declarations match the reference xxhash.h, so existing callers only
+ * need to link against libxxhash_capi instead of compiling xxhash.c. The
+ * state types are opaque: always use XXH32_createState / XXH64_createState.
+ * A new state hashes with seed 0 until it is reset. A NULL state makes
+ * update and reset return XXH_ERROR and digest return 0.
+ */
+
+#ifndef XXHASH_H
+#define XXHASH_H
+
+#include <stddef.h>   /* size_t */
+
+#if defined (__cplusplus)
+extern "C" {
+#endif
+
+typedef enum { XXH_OK=0, XXH_ERROR } XXH_errorcode;
+
+
+/* Simple hash functions */
+
+unsigned int       XXH32 (const void* input, size_t length, unsigned int seed);
+unsigned long long XXH64 (const void* input, size_t length, unsigned long long seed);
+
+
+/* Streaming */
+
+typedef struct XXH32_state_s XXH32_state_t;
+typedef struct XXH64_state_s XXH64_state_t;
+
+XXH32_state_t* XXH32_createState(void);
+XXH_errorcode  XXH32_freeState(XXH32_state_t* statePtr);
+
+XXH64_state_t* XXH64_createState(void);
+XXH_errorcode  XXH64_freeState(XXH64_state_t* statePtr);
+
+XXH_errorcode XXH32_reset  (XXH32_state_t* statePtr, unsigned int seed);
+XXH_errorcode XXH32_update (XXH32_state_t* statePtr, const void* input, size_t length);
+unsigned int  XXH32_digest (const XXH32_state_t* statePtr);
+
+XXH_errorcode      XXH64_reset  (XXH64_state_t* statePtr, unsigned long long seed);
+XXH_errorcode      XXH64_update (XXH64_state_t* statePtr, const void* input, size_t length);
+unsigned long long XXH64_digest (const XXH64_state_t* statePtr);
+
+
+#if defined (__cplusplus)
+}
+#endif
+
+#endif /* XXHASH_H */
@@ -0,0 +1,171 @@
+//! C bindings with the same names and signatures as the reference
+//! `xxhash.h`, so C code can link against `libxxhash_capi` instead of the
+//! C library. A crate of its own so that only C users build the static
+//! and dynamic libraries; the header lives in `include/xxhash.h`.
+//!
+//! The state types are opaque: allocate them with `XXH*_createState`.
+//! Every function taking a state accepts NULL: `*_update` and `*_reset`
+//! fail with `XXH_ERROR`, `*_digest` returns 0, `*_freeState` does nothing.
+
+#![allow(non_snake_case, non_camel_case_types)]
+#![allow(unstable)]
+
+extern crate xxhash;
+
+use std::hash::{Hasher, Writer};
+use std::mem::transmute;
+use std::raw::Slice;
+
+use xxhash::XXHasher;
+use xxhash::xxh32;
+
+use self::XXH_errorcode::{XXH_OK, XXH_ERROR};
+
+#[repr(C)]
+#[derive(Copy, PartialEq, Show)]
+pub enum XXH_errorcode {
+    XXH_OK = 0,
+    XXH_ERROR = 1,
+}
+
+pub type XXH32_state_t = xxh32::XXHasher;
+pub type XXH64_state_t = XXHasher;
+
+// C callers are allowed to pass NULL along with a zero length.
+unsafe fn bytes<'a>(input: *const u8, length: usize) -> &'a [u8] { #![inline]
+    if length == 0 {
+        &[]
+    } else {
+        transmute(Slice { data: input, len: length })
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH32(input: *const u8, length: usize, seed: u32) -> u32 {
+    xxh32::oneshot(bytes(input, length), seed)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH64(input: *const u8, length: usize, seed: u64) -> u64 {
+    xxhash::oneshot(bytes(input, length), seed)
+}
+
+#[no_mangle]
+pub extern "C" fn XXH32_createState() -> *mut XXH32_state_t {
+    unsafe { transmute(Box::new(xxh32::XXHasher::new_with_seed(0))) }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH32_freeState(state: *mut XXH32_state_t) -> XXH_errorcode {
+    if !state.is_null() {
+        let _: Box<XXH32_state_t> = transmute(state);
+    }
+    XXH_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH32_reset(state: *mut XXH32_state_t, seed: u32) -> XXH_errorcode {
+    if state.is_null() { return XXH_ERROR; }
+    *state = xxh32::XXHasher::new_with_seed(seed);
+    XXH_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH32_update(state: *mut XXH32_state_t,
+                                      input: *const u8, length: usize) -> XXH_errorcode {
+    if state.is_null() || (input.is_null() && length != 0) { return XXH_ERROR; }
+    (*state).write(bytes(input, length));
+    XXH_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH32_digest(state: *const XXH32_state_t) -> u32 {
+    if state.is_null() { return 0; }
+    (*state).finish()
+}
+
+#[no_mangle]
+pub extern "C" fn XXH64_createState() -> *mut XXH64_state_t {
+    unsafe { transmute(Box::new(XXHasher::new_with_seed(0))) }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH64_freeState(state: *mut XXH64_state_t) -> XXH_errorcode {
+    if !state.is_null() {
+        let _: Box<XXH64_state_t> = transmute(state);
+    }
+    XXH_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH64_reset(state: *mut XXH64_state_t, seed: u64) -> XXH_errorcode {
+    if state.is_null() { return XXH_ERROR; }
+    *state = XXHasher::new_with_seed(seed);
+    XXH_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH64_update(state: *mut XXH64_state_t,
+                                      input: *const u8, length: usize) -> XXH_errorcode {
+    if state.is_null() || (input.is_null() && length != 0) { return XXH_ERROR; }
+    (*state).write(bytes(input, length));
+    XXH_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn XXH64_digest(state: *const XXH64_state_t) -> u64 {
+    if state.is_null() { return 0; }
+    (*state).finish()
+}
+
+#[test]
+fn test_oneshot() {
+    let data = b"Nobody inspects the spammish repetition";
+    unsafe {
+        assert_eq!(XXH32(data.as_ptr(), data.len(), 0), xxh32::oneshot(data, 0));
+        assert_eq!(XXH64(data.as_ptr(), data.len(), 0), xxhash::oneshot(data, 0));
+        assert_eq!(XXH64(0 as *const u8, 0, 0), xxhash::oneshot(&[], 0));
+    }
+}
+
+#[test]
+fn test_streaming() {
+    let data = b"Nobody inspects the spammish repetition";
+    unsafe {
+        let s32 = XXH32_createState();
+        let s64 = XXH64_createState();
+        assert_eq!(XXH32_reset(s32, 7), XXH_OK);
+        assert_eq!(XXH64_reset(s64, 7), XXH_OK);
+        for chunk in data.chunks(5) {
+            assert_eq!(XXH32_update(s32, chunk.as_ptr(), chunk.len()), XXH_OK);
+            assert_eq!(XXH64_update(s64, chunk.as_ptr(), chunk.len()), XXH_OK);
+        }
+        assert_eq!(XXH32_digest(s32), xxh32::oneshot(data, 7));
+        assert_eq!(XXH64_digest(s64), xxhash::oneshot(data, 7));
+
+        assert_eq!(XXH32_update(s32, 0 as *const u8, 1), XXH_ERROR);
+        assert_eq!(XXH64_reset(0 as *mut XXH64_state_t, 0), XXH_ERROR);
+        assert_eq!(XXH32_update(0 as *mut XXH32_state_t, data.as_ptr(), data.len()), XXH_ERROR);
+        assert_eq!(XXH64_update(0 as *mut XXH64_state_t, data.as_ptr(), data.len()), XXH_ERROR);
+        assert_eq!(XXH32_digest(0 as *const XXH32_state_t), 0);
+        assert_eq!(XXH64_digest(0 as *const XXH64_state_t), 0);
+
+        assert_eq!(XXH32_freeState(s32), XXH_OK);
+        assert_eq!(XXH64_freeState(s64), XXH_OK);
+    }
+}
+
+#[test]
+fn test_fresh_state_is_seed_0() {
+    let data = b"abc";
+    unsafe {
+        let s32 = XXH32_createState();
+        let s64 = XXH64_createState();
+        XXH32_update(s32, data.as_ptr(), data.len());
+        XXH64_update(s64, data.as_ptr(), data.len());
+        assert_eq!(XXH32_digest(s32), xxh32::oneshot(data, 0));
+        assert_eq!(XXH64_digest(s64), xxhash::oneshot(data, 0));
+        XXH32_freeState(s32);
+        XXH64_freeState(s64);
+    }
+}
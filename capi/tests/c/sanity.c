@@ -0,0 +1,79 @@
+/*
+ * The official sanity test, run through the C interface.
+ * Built and run by tests/capi.rs.
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include "xxhash.h"
+
+#define BUFSIZE 101
+#define PRIME 2654435761U
+
+static unsigned char buf[BUFSIZE];
+static int failures = 0;
+
+static void check32(size_t len, unsigned int seed, unsigned int expected)
+{
+    XXH32_state_t* state = XXH32_createState();
+    size_t i;
+
+    if (XXH32(buf, len, seed) != expected) {
+        printf("XXH32(%u, %u) failed\n", (unsigned)len, seed);
+        failures++;
+    }
+
+    XXH32_reset(state, seed);
+    for (i = 0; i < len; i++) XXH32_update(state, buf + i, 1);
+    if (XXH32_digest(state) != expected) {
+        printf("XXH32 streaming (%u, %u) failed\n", (unsigned)len, seed);
+        failures++;
+    }
+    XXH32_freeState(state);
+}
+
+static void check64(size_t len, unsigned long long seed, unsigned long long expected)
+{
+    XXH64_state_t* state = XXH64_createState();
+    size_t i;
+
+    if (XXH64(buf, len, seed) != expected) {
+        printf("XXH64(%u, %llu) failed\n", (unsigned)len, seed);
+        failures++;
+    }
+
+    XXH64_reset(state, seed);
+    for (i = 0; i < len; i++) XXH64_update(state, buf + i, 1);
+    if (XXH64_digest(state) != expected) {
+        printf("XXH64 streaming (%u, %llu) failed\n", (unsigned)len, seed);
+        failures++;
+    }
+    XXH64_freeState(state);
+}
+
+int main(void)
+{
+    unsigned int random = PRIME;
+    int i;
+
+    for (i = 0; i < BUFSIZE; i++) {
+        buf[i] = (unsigned char)(random >> 24);
+        random *= random;
+    }
+
+    check32(1,       0,     0xB85CBEE5U);
+    check32(1,       PRIME, 0xD5845D64U);
+    check32(14,      0,     0xE5AA0AB4U);
+    check32(14,      PRIME, 0x4481951DU);
+    check32(BUFSIZE, 0,     0x1F1AA412U);
+    check32(BUFSIZE, PRIME, 0x498EC8E2U);
+
+    check64(1,       0,     0x4FCE394CC88952D8ULL);
+    check64(1,       PRIME, 0x739840CB819FA723ULL);
+    check64(14,      0,     0xCFFA8DB881BC3A3DULL);
+    check64(14,      PRIME, 0x5B9611585EFCC9CBULL);
+    check64(BUFSIZE, 0,     0x0EAB543384F878ADULL);
+    check64(BUFSIZE, PRIME, 0xCAA65939306F1E21ULL);
+
+    return failures == 0 ? EXIT_SUCCESS : EXIT_FAILURE;
+}
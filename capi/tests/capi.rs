@@ -0,0 +1,36 @@
+//! Compile the C programs in `tests/c` against the static library and the
+//! shipped header, and run them.
+
+#![allow(unstable)]
+
+use std::io::process::Command;
+use std::io::fs::PathExtensions;
+
+fn staticlib(root: &Path) -> Path {
+    // newer cargo puts build outputs below `target/debug`
+    let nested = root.join("target/debug/libxxhash_capi.a");
+    if nested.exists() { nested } else { root.join("target/libxxhash_capi.a") }
+}
+
+fn run_c(name: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let exe = root.join("target").join(name);
+
+    let built = Command::new("cc")
+        .arg(root.join("tests/c").join(format!("{}.c", name)))
+        .arg("-I").arg(root.join("include"))
+        .arg("-o").arg(&exe)
+        .arg(staticlib(&root))
+        .args(&["-lpthread", "-ldl", "-lm"])
+        .status().unwrap();
+    assert!(built.success(), "failed to compile {}.c", name);
+
+    let output = Command::new(&exe).output().unwrap();
+    assert!(output.status.success(), "{}: {}", name,
+            String::from_utf8_lossy(output.output.as_slice()));
+}
+
+#[test]
+fn test_c_sanity() {
+    run_c("sanity");
+}
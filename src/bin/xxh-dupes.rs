@@ -0,0 +1,142 @@
+//! Report duplicate files under the given paths.
+
+#![allow(unstable)]
+
+extern crate xxhash;
+
+use std::default::Default;
+use std::io::stdio::stderr;
+use std::os;
+use std::path::Path;
+use std::str::FromStr;
+
+use xxhash::dupes::{find_duplicates, DupeOptions, DupeReport};
+
+static USAGE: &'static str = "\
+usage: xxh-dupes [options] PATH...
+
+  -j N              hashing threads (default: one per core)
+  --json            print the report as JSON
+  --prefix BYTES    how much of each file the first hash reads (default 4096)
+  --min-size BYTES  skip smaller files (default 1)
+  --no-hard-links   count hard links to one file as separate files";
+
+struct Args {
+    options: DupeOptions,
+    json: bool,
+    roots: Vec<Path>,
+}
+
+fn main() {
+    let args = match parse_args(os::args().tail()) {
+        Ok(Some(args)) => args,
+        Ok(None) => return println!("{}", USAGE),
+        Err(msg) => {
+            let _ = writeln!(&mut stderr(), "xxh-dupes: {}\n\n{}", msg, USAGE);
+            return os::set_exit_status(2);
+        }
+    };
+
+    let report = find_duplicates(args.roots.as_slice(), &args.options);
+    if args.json {
+        print_json(&report);
+    } else {
+        print_text(&report);
+    }
+    if !report.errors.is_empty() {
+        os::set_exit_status(1);
+    }
+}
+
+// `None` when help was asked for.
+fn parse_args(args: &[String]) -> Result<Option<Args>, String> {
+    let mut parsed = Args { options: Default::default(), json: false, roots: Vec::new() };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let arg = arg.as_slice();
+        match arg {
+            "-j" => parsed.options.threads = try!(number(arg, iter.next())),
+            "--prefix" => parsed.options.prefix_len = try!(number(arg, iter.next())),
+            "--min-size" => parsed.options.min_size = try!(number(arg, iter.next())),
+            "--json" => parsed.json = true,
+            "--no-hard-links" => parsed.options.hard_links = false,
+            "-h" | "--help" => return Ok(None),
+            _ if arg.starts_with("-") => return Err(format!("unknown option {}", arg)),
+            _ => parsed.roots.push(Path::new(arg)),
+        }
+    }
+    if parsed.roots.is_empty() {
+        return Err(String::from_str("no paths given"));
+    }
+    Ok(Some(parsed))
+}
+
+fn number<T: FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    value.and_then(|v| v.parse()).ok_or_else(|| format!("{} needs a number", flag))
+}
+
+fn print_text(report: &DupeReport) {
+    let mut wasted = 0;
+    for group in report.groups.iter() {
+        println!("{} bytes each, xxh64 {:016x}:", group.size, group.hash);
+        for file in group.files.iter() {
+            // hard links to the same file share a line
+            let paths: Vec<String> = file.paths.iter().map(|p| p.display().to_string()).collect();
+            println!("  {}", paths.connect(" = "));
+        }
+        println!("");
+        wasted += group.wasted();
+    }
+    let mut err = stderr();
+    for &(ref path, ref e) in report.errors.iter() {
+        let _ = writeln!(&mut err, "xxh-dupes: {}: {}", path.display(), e);
+    }
+    let _ = writeln!(&mut err, "{} files scanned, {} groups of duplicates, {} bytes reclaimable",
+                     report.scanned, report.groups.len(), wasted);
+}
+
+fn print_json(report: &DupeReport) {
+    let mut out = String::from_str("{\"groups\":[");
+    for (i, group) in report.groups.iter().enumerate() {
+        if i > 0 { out.push(','); }
+        out.push_str(format!("{{\"size\":{},\"xxh64\":\"{:016x}\",\"files\":[",
+                             group.size, group.hash).as_slice());
+        for (j, file) in group.files.iter().enumerate() {
+            if j > 0 { out.push(','); }
+            out.push('[');
+            for (k, path) in file.paths.iter().enumerate() {
+                if k > 0 { out.push(','); }
+                push_json_string(&mut out, path.display().to_string().as_slice());
+            }
+            out.push(']');
+        }
+        out.push_str("]}");
+    }
+    out.push_str("],\"errors\":[");
+    for (i, &(ref path, ref e)) in report.errors.iter().enumerate() {
+        if i > 0 { out.push(','); }
+        out.push_str("{\"path\":");
+        push_json_string(&mut out, path.display().to_string().as_slice());
+        out.push_str(",\"error\":");
+        push_json_string(&mut out, e.to_string().as_slice());
+        out.push('}');
+    }
+    out.push_str(format!("],\"scanned\":{}}}", report.scanned).as_slice());
+    println!("{}", out);
+}
+
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(format!("\\u{:04x}", c as u32).as_slice()),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
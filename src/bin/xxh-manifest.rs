@@ -0,0 +1,127 @@
+//! Snapshot a directory tree's file hashes, and later report what changed.
+
+#![allow(unstable)]
+
+extern crate xxhash;
+
+use std::io::stdio::{stdout, stderr};
+use std::os;
+use std::path::Path;
+
+use xxhash::manifest::{Manifest, Changes, escape_path};
+
+static USAGE: &'static str = "\
+usage: xxh-manifest create [-j N] [-o FILE] DIR
+       xxh-manifest compare [-j N] [--trust-mtime] MANIFEST DIR
+
+  -j N           hashing threads (default: one per core)
+  -o FILE        write the manifest to FILE instead of stdout
+  --trust-mtime  don't re-hash files whose size and mtime match the manifest
+
+compare lists changes as `A path`, `D path`, `M path` and `R from -> to`,
+with paths escaped as in the manifest, and exits with 1 if there are any.";
+
+struct Args {
+    compare: bool,
+    threads: usize,
+    output: Option<Path>,
+    trust_mtime: bool,
+    paths: Vec<Path>,
+}
+
+fn main() {
+    let args = match parse_args(os::args().tail()) {
+        Ok(Some(args)) => args,
+        Ok(None) => return println!("{}", USAGE),
+        Err(msg) => return fail(2, format!("{}\n\n{}", msg, USAGE)),
+    };
+
+    if !args.compare {
+        let manifest = match Manifest::generate(&args.paths[0], args.threads) {
+            Ok(manifest) => manifest,
+            Err(e) => return fail(2, format!("{}: {}", args.paths[0].display(), e)),
+        };
+        let written = match args.output {
+            Some(ref path) => manifest.save(path),
+            None => manifest.write(&mut stdout()),
+        };
+        if let Err(e) = written {
+            fail(2, format!("writing the manifest: {}", e));
+        }
+        return;
+    }
+
+    let old = match Manifest::load(&args.paths[0]) {
+        Ok(manifest) => manifest,
+        Err(e) => return fail(2, format!("{}: {}", args.paths[0].display(), e)),
+    };
+    let now = if args.trust_mtime {
+        old.update(&args.paths[1], args.threads)
+    } else {
+        Manifest::generate(&args.paths[1], args.threads)
+    };
+    let now = match now {
+        Ok(manifest) => manifest,
+        Err(e) => return fail(2, format!("{}: {}", args.paths[1].display(), e)),
+    };
+    let changes = old.diff(&now);
+    print_changes(&changes);
+    if !changes.is_empty() {
+        os::set_exit_status(1);
+    }
+}
+
+fn fail(status: isize, msg: String) {
+    let _ = writeln!(&mut stderr(), "xxh-manifest: {}", msg);
+    os::set_exit_status(status);
+}
+
+// `None` when help was asked for.
+fn parse_args(args: &[String]) -> Result<Option<Args>, String> {
+    let mut parsed = Args { compare: false, threads: 0, output: None, trust_mtime: false, paths: Vec::new() };
+    let mut iter = args.iter();
+    match iter.next().map(|cmd| cmd.as_slice()) {
+        Some("create") => {}
+        Some("compare") => parsed.compare = true,
+        Some("-h") | Some("--help") => return Ok(None),
+        Some(cmd) => return Err(format!("unknown command {}", cmd)),
+        None => return Err(String::from_str("no command given")),
+    }
+    while let Some(arg) = iter.next() {
+        let arg = arg.as_slice();
+        match arg {
+            "-j" => parsed.threads = match iter.next().and_then(|v| v.parse()) {
+                Some(n) => n,
+                None => return Err(String::from_str("-j needs a number")),
+            },
+            "-o" if !parsed.compare => parsed.output = match iter.next() {
+                Some(path) => Some(Path::new(path.as_slice())),
+                None => return Err(String::from_str("-o needs a file")),
+            },
+            "--trust-mtime" if parsed.compare => parsed.trust_mtime = true,
+            "-h" | "--help" => return Ok(None),
+            _ if arg.starts_with("-") => return Err(format!("unknown option {}", arg)),
+            _ => parsed.paths.push(Path::new(arg)),
+        }
+    }
+    let wanted = if parsed.compare { 2 } else { 1 };
+    if parsed.paths.len() != wanted {
+        return Err(format!("expected {} paths, got {}", wanted, parsed.paths.len()));
+    }
+    Ok(Some(parsed))
+}
+
+fn print_changes(changes: &Changes) {
+    for path in changes.added.iter() {
+        println!("A {}", escape_path(path.as_slice()));
+    }
+    for path in changes.removed.iter() {
+        println!("D {}", escape_path(path.as_slice()));
+    }
+    for path in changes.modified.iter() {
+        println!("M {}", escape_path(path.as_slice()));
+    }
+    for &(ref from, ref to) in changes.moved.iter() {
+        println!("R {} -> {}", escape_path(from.as_slice()), escape_path(to.as_slice()));
+    }
+}
@@ -0,0 +1,291 @@
+//! Print or benchmark xxHash digests, after the reference `xxhsum`.
+
+#![allow(unstable)]
+
+extern crate test;
+extern crate xxhash;
+
+use std::io::stdio::{stdin, stderr};
+use std::iter::range;
+use std::os;
+use std::path::Path;
+use std::time::Duration;
+
+use test::black_box;
+
+use xxhash::Algorithm;
+use xxhash::io::{hash_files, hash_reader, hash_reader32};
+
+static USAGE: &'static str = "\
+usage: xxhsum [-H0|-H1] [-j#] [FILE...]
+       xxhsum -b[#] [-i#] [-B#] [--csv|--json]
+
+  -H0     xxh32
+  -H1     xxh64 (default)
+  -j#     hash # files at once, 0 for one per core (default 1)
+  -       read standard input, as no FILE does
+
+  -b      benchmark every algorithm, or only algorithm # of:
+            1 xxh32, 2 xxh32 unaligned, 3 xxh64, 4 xxh64 unaligned
+  -i#     seconds to time each measurement for (default 1)
+  -B#     only this input size, with an optional K or M suffix
+  --csv   print the benchmark as CSV
+  --json  print the benchmark as JSON";
+
+#[derive(Copy, PartialEq)]
+enum Format {
+    Text,
+    Csv,
+    Json,
+}
+
+struct Args {
+    algorithm: Algorithm,
+    files: Vec<String>,
+    threads: usize,
+    bench: Option<Option<usize>>,
+    seconds: f64,
+    size: Option<usize>,
+    format: Format,
+}
+
+fn main() {
+    let args = match parse_args(os::args().tail()) {
+        Ok(Some(args)) => args,
+        Ok(None) => return println!("{}", USAGE),
+        Err(msg) => {
+            let _ = writeln!(&mut stderr(), "xxhsum: {}\n\n{}", msg, USAGE);
+            return os::set_exit_status(2);
+        }
+    };
+    match args.bench {
+        Some(id) => bench(id, &args),
+        None => sum(&args),
+    }
+}
+
+// `None` when help was asked for.
+fn parse_args(args: &[String]) -> Result<Option<Args>, String> {
+    let mut parsed = Args {
+        algorithm: Algorithm::Xxh64,
+        files: Vec::new(),
+        threads: 1,
+        bench: None,
+        seconds: 1.0,
+        size: None,
+        format: Format::Text,
+    };
+    let mut iter = args.iter();
+    let mut options = true;
+    while let Some(arg) = iter.next() {
+        let arg = arg.as_slice();
+        if !options || arg == "-" || !arg.starts_with("-") {
+            parsed.files.push(String::from_str(arg));
+            continue;
+        }
+        match arg {
+            "--" => options = false,
+            "-H0" => parsed.algorithm = Algorithm::Xxh32,
+            "-H1" => parsed.algorithm = Algorithm::Xxh64,
+            "--csv" => parsed.format = Format::Csv,
+            "--json" => parsed.format = Format::Json,
+            "-h" | "--help" => return Ok(None),
+            "-b" => parsed.bench = Some(None),
+            _ if arg.starts_with("-b") => match arg.slice_from(2).parse() {
+                Some(id) if id >= 1 && id <= BENCHES.len() => parsed.bench = Some(Some(id)),
+                _ => return Err(format!("no benchmark {}", arg.slice_from(2))),
+            },
+            "-j" => parsed.threads = match iter.next().and_then(|n| n.parse()) {
+                Some(threads) => threads,
+                None => return Err(String::from_str("-j needs a number")),
+            },
+            _ if arg.starts_with("-j") => match arg.slice_from(2).parse() {
+                Some(threads) => parsed.threads = threads,
+                None => return Err(format!("bad thread count {}", arg.slice_from(2))),
+            },
+            _ if arg.starts_with("-i") => match arg.slice_from(2).parse::<f64>() {
+                Some(seconds) if seconds > 0.0 => parsed.seconds = seconds,
+                _ => return Err(format!("bad time {}", arg.slice_from(2))),
+            },
+            _ if arg.starts_with("-B") => match parse_size(arg.slice_from(2)) {
+                Some(size) => parsed.size = Some(size),
+                None => return Err(format!("bad size {}", arg.slice_from(2))),
+            },
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if parsed.bench.is_some() && !parsed.files.is_empty() {
+        return Err(String::from_str("the benchmark takes no files"));
+    }
+    Ok(Some(parsed))
+}
+
+// "100", "64K", "16M"
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, unit) = if s.ends_with("K") {
+        (s.slice_to(s.len() - 1), 1024)
+    } else if s.ends_with("M") {
+        (s.slice_to(s.len() - 1), 1024 * 1024)
+    } else {
+        (s, 1)
+    };
+    digits.parse::<usize>().map(|n| n * unit)
+}
+
+fn sum(args: &Args) {
+    let stdin_only = vec![String::from_str("-")];
+    let names = if args.files.is_empty() { &stdin_only } else { &args.files };
+
+    // the files all at once, then standard input in its place
+    let paths: Vec<Path> = names.iter()
+        .filter(|name| name.as_slice() != "-")
+        .map(|name| Path::new(name.as_slice()))
+        .collect();
+    let mut hashes = hash_files(paths.as_slice(), args.algorithm, 0, args.threads).into_iter();
+
+    for name in names.iter() {
+        let digest = if name.as_slice() == "-" {
+            match args.algorithm {
+                Algorithm::Xxh32 => hash_reader32(&mut stdin(), 0).map(|h| h as u64),
+                Algorithm::Xxh64 => hash_reader(&mut stdin(), 0),
+            }
+        } else {
+            hashes.next().unwrap()
+        };
+        match digest {
+            Ok(digest) => match args.algorithm {
+                Algorithm::Xxh32 => println!("{:08x}  {}", digest, name),
+                Algorithm::Xxh64 => println!("{:016x}  {}", digest, name),
+            },
+            Err(e) => {
+                let _ = writeln!(&mut stderr(), "xxhsum: {}: {}", name, e);
+                os::set_exit_status(1);
+            }
+        }
+    }
+}
+
+struct Bench {
+    name: &'static str,
+    algorithm: Algorithm,
+    unaligned: bool,
+}
+
+// numbered from 1, as `-b#` picks them
+static BENCHES: [Bench; 4] = [
+    Bench { name: "xxh32", algorithm: Algorithm::Xxh32, unaligned: false },
+    Bench { name: "xxh32 unaligned", algorithm: Algorithm::Xxh32, unaligned: true },
+    Bench { name: "xxh64", algorithm: Algorithm::Xxh64, unaligned: false },
+    Bench { name: "xxh64 unaligned", algorithm: Algorithm::Xxh64, unaligned: true },
+];
+
+static SIZES: [usize; 11] = [
+    1, 4, 16, 64, 256, 1024, 4 * 1024, 16 * 1024, 100 * 1024, 1024 * 1024, 16 * 1024 * 1024,
+];
+
+struct Measurement {
+    id: usize,
+    size: usize,
+    hashes_per_sec: f64,
+}
+
+fn bench(only: Option<usize>, args: &Args) {
+    let one_size = args.size.map(|size| [size]);
+    let sizes = match one_size {
+        Some(ref size) => size.as_slice(),
+        None => SIZES.as_slice(),
+    };
+    let max = sizes.iter().fold(0, |max, size| std::cmp::max(max, *size));
+
+    // room to start at an 8-byte boundary, and one past it
+    let mut x = 1u64;
+    let buf: Vec<u8> = range(0, max + 9).map(|_| {
+        x = x * 6364136223846793005 + 1442695040888963407;
+        (x >> 56) as u8
+    }).collect();
+    let aligned = (8 - buf.as_ptr() as usize % 8) % 8;
+
+    if args.format == Format::Csv {
+        println!("id,algorithm,aligned,size,mb_per_s,hashes_per_s");
+    }
+    let mut results = Vec::new();
+    for (i, bench) in BENCHES.iter().enumerate() {
+        let id = i + 1;
+        if only.map_or(false, |only| only != id) {
+            continue;
+        }
+        let start = if bench.unaligned { aligned + 1 } else { aligned };
+        for &size in sizes.iter() {
+            let input = buf.slice(start, start + size);
+            let hashes_per_sec = measure(bench.algorithm, input, args.seconds);
+            let result = Measurement { id: id, size: size, hashes_per_sec: hashes_per_sec };
+            match args.format {
+                Format::Text => println!("{:>2} {:<16} {:>9} {:>10.1} MB/s {:>14.0} hashes/s",
+                                         id, bench.name, size_label(size),
+                                         mb_per_sec(&result), result.hashes_per_sec),
+                Format::Csv => println!("{},{},{},{},{:.1},{:.0}", id, bench.algorithm_name(),
+                                        !bench.unaligned, size, mb_per_sec(&result),
+                                        result.hashes_per_sec),
+                Format::Json => {}
+            }
+            results.push(result);
+        }
+    }
+    if args.format == Format::Json {
+        print_json(results.as_slice());
+    }
+}
+
+// Hashes per second, over batches that double until one takes `seconds`.
+fn measure(algorithm: Algorithm, input: &[u8], seconds: f64) -> f64 {
+    let target = seconds * 1e9;
+    let mut iterations = 1u64;
+    loop {
+        let elapsed = Duration::span(|| {
+            for _ in range(0, iterations) {
+                black_box(algorithm.oneshot(black_box(input), 0));
+            }
+        });
+        let ns = elapsed.num_nanoseconds().unwrap_or(std::i64::MAX) as f64;
+        if ns >= target {
+            return iterations as f64 * 1e9 / ns;
+        }
+        iterations *= 2;
+    }
+}
+
+fn mb_per_sec(result: &Measurement) -> f64 {
+    result.hashes_per_sec * result.size as f64 / 1e6
+}
+
+fn size_label(size: usize) -> String {
+    if size >= 1024 * 1024 && size % (1024 * 1024) == 0 {
+        format!("{} MiB", size / (1024 * 1024))
+    } else if size >= 1024 && size % 1024 == 0 {
+        format!("{} KiB", size / 1024)
+    } else {
+        format!("{} B", size)
+    }
+}
+
+fn print_json(results: &[Measurement]) {
+    println!("[");
+    for (i, result) in results.iter().enumerate() {
+        let bench = &BENCHES[result.id - 1];
+        println!("  {{\"id\":{},\"algorithm\":\"{}\",\"aligned\":{},\"size\":{},\
+                  \"mb_per_s\":{:.1},\"hashes_per_s\":{:.0}}}{}",
+                 result.id, bench.algorithm_name(), !bench.unaligned, result.size,
+                 mb_per_sec(result), result.hashes_per_sec,
+                 if i + 1 < results.len() { "," } else { "" });
+    }
+    println!("]");
+}
+
+impl Bench {
+    fn algorithm_name(&self) -> &'static str {
+        match self.algorithm {
+            Algorithm::Xxh32 => "xxh32",
+            Algorithm::Xxh64 => "xxh64",
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Flat per-block hash lists, in the style of BitTorrent pieces, for
+//! resumable transfers: which blocks of a file differ, and which of a
+//! partial download still need fetching.
+//!
+//! Each block is hashed with `oneshot` (seed 0); the last one may be
+//! short. The serialized form is
+//!
+//!     "XXBM" | version u8 | 3 zero bytes | block size u32 LE |
+//!     file length u64 LE | xxh64 u64 LE per block
+
+use core::prelude::*;
+use core::cmp::{min, max};
+use core::iter::{range, repeat};
+use core::slice::bytes::copy_memory;
+
+use std::io::{Reader, IoResult};
+use std::io::fs::File;
+use std::path::Path;
+use std::vec::Vec;
+
+use bytes::{le32, le64, put_le32, put_le64};
+use io::read_full;
+
+#[cfg(test)] use std::io::BufReader;
+
+const MAGIC: &'static [u8] = b"XXBM";
+
+pub const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 20;
+
+/// One block of a manifest.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct Block {
+    pub index: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub hash: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct BlockManifest {
+    block_size: u32,
+    len: u64,
+    hashes: Vec<u64>,
+}
+
+impl BlockManifest {
+    /// Panics if `block_size` is zero.
+    pub fn new(data: &[u8], block_size: u32) -> BlockManifest {
+        assert!(block_size > 0);
+        BlockManifest {
+            block_size: block_size,
+            len: data.len() as u64,
+            hashes: data.chunks(block_size as usize).map(|b| ::oneshot(b, 0)).collect(),
+        }
+    }
+
+    pub fn from_reader<R: Reader>(reader: &mut R, block_size: u32) -> IoResult<BlockManifest> {
+        assert!(block_size > 0);
+        let mut buf: Vec<u8> = repeat(0).take(block_size as usize).collect();
+        let mut manifest = BlockManifest { block_size: block_size, len: 0, hashes: Vec::new() };
+        loop {
+            let n = try!(read_full(reader, buf.as_mut_slice()));
+            if n == 0 {
+                return Ok(manifest);
+            }
+            manifest.len += n as u64;
+            manifest.hashes.push(::oneshot(buf.slice_to(n), 0));
+            if n < buf.len() {
+                return Ok(manifest);
+            }
+        }
+    }
+
+    pub fn from_file(path: &Path, block_size: u32) -> IoResult<BlockManifest> {
+        BlockManifest::from_reader(&mut try!(File::open(path)), block_size)
+    }
+
+    pub fn block_size(&self) -> u32 { #![inline]
+        self.block_size
+    }
+
+    /// The length of the whole file.
+    pub fn len(&self) -> u64 { #![inline]
+        self.len
+    }
+
+    pub fn block_count(&self) -> u64 { #![inline]
+        self.hashes.len() as u64
+    }
+
+    pub fn block(&self, index: u64) -> Option<Block> {
+        let hash = match self.hashes.get(index as usize) { Some(h) => *h, None => return None };
+        let offset = index * self.block_size as u64;
+        Some(Block {
+            index: index,
+            offset: offset,
+            len: min(self.block_size as u64, self.len - offset),
+            hash: hash,
+        })
+    }
+
+    pub fn blocks<'a>(&'a self) -> Blocks<'a> { #![inline]
+        Blocks { manifest: self, index: 0 }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = repeat(0).take(HEADER_LEN + 8 * self.hashes.len()).collect();
+        copy_memory(out.slice_to_mut(4), MAGIC);
+        out[4] = VERSION;
+        put_le32(out.as_mut_slice(), 8, self.block_size);
+        put_le64(out.as_mut_slice(), 12, self.len);
+        for (i, hash) in self.hashes.iter().enumerate() {
+            put_le64(out.as_mut_slice(), HEADER_LEN + 8 * i, *hash);
+        }
+        out
+    }
+
+    /// `None` unless `bytes` is a whole manifest, with a hash for every
+    /// block.
+    pub fn from_bytes(bytes: &[u8]) -> Option<BlockManifest> {
+        if bytes.len() < HEADER_LEN || bytes.slice_to(4) != MAGIC || bytes[4] != VERSION
+            || bytes.slice(5, 8) != [0u8, 0, 0].as_slice() {
+            return None;
+        }
+        let block_size = le32(bytes, 8);
+        let len = le64(bytes, 12);
+        if block_size == 0 {
+            return None;
+        }
+        let count = (len + block_size as u64 - 1) / block_size as u64;
+        if ((bytes.len() - HEADER_LEN) / 8) as u64 != count || (bytes.len() - HEADER_LEN) % 8 != 0 {
+            return None;
+        }
+        let hashes = range(0, count as usize).map(|i| le64(bytes, HEADER_LEN + 8 * i)).collect();
+        Some(BlockManifest { block_size: block_size, len: len, hashes: hashes })
+    }
+
+    /// The byte ranges where the file `other` describes differs from this
+    /// one, merged and in order. A length change counts as a difference
+    /// over the bytes only the longer file has. `None` if the block sizes
+    /// differ.
+    pub fn diff(&self, other: &BlockManifest) -> Option<Vec<ByteRange>> {
+        if self.block_size != other.block_size {
+            return None;
+        }
+        let bs = self.block_size as u64;
+        let end = max(self.len, other.len);
+        let count = max(self.hashes.len(), other.hashes.len());
+
+        let mut ranges: Vec<ByteRange> = Vec::new();
+        for i in range(0, count) {
+            // the last blocks of files of different lengths differ even
+            // when their bytes hash the same
+            let same = self.hashes.get(i) == other.hashes.get(i)
+                && self.block(i as u64).map(|b| b.len) == other.block(i as u64).map(|b| b.len);
+            if same {
+                continue;
+            }
+            let offset = i as u64 * bs;
+            let len = min(bs, end - offset);
+            if let Some(last) = ranges.last_mut() {
+                if last.offset + last.len == offset {
+                    last.len += len;
+                    continue;
+                }
+            }
+            ranges.push(ByteRange { offset: offset, len: len });
+        }
+        Some(ranges)
+    }
+
+    /// The indices of the blocks a partial download in `reader` lacks or
+    /// has wrong. Whatever isn't there yet counts as missing, be it past
+    /// the end of the file or still zero-filled.
+    pub fn missing<R: Reader>(&self, reader: &mut R) -> IoResult<Vec<u64>> {
+        let mut buf: Vec<u8> = repeat(0).take(self.block_size as usize).collect();
+        let mut missing = Vec::new();
+        let mut eof = false;
+        for block in self.blocks() {
+            let n = if eof { 0 } else { try!(read_full(reader, buf.slice_to_mut(block.len as usize))) };
+            eof = eof || n < block.len as usize;
+            if n < block.len as usize || ::oneshot(buf.slice_to(n), 0) != block.hash {
+                missing.push(block.index);
+            }
+        }
+        Ok(missing)
+    }
+
+    pub fn missing_in_file(&self, path: &Path) -> IoResult<Vec<u64>> {
+        self.missing(&mut try!(File::open(path)))
+    }
+}
+
+pub struct Blocks<'a> {
+    manifest: &'a BlockManifest,
+    index: u64,
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        let block = self.manifest.block(self.index);
+        if block.is_some() {
+            self.index += 1;
+        }
+        block
+    }
+}
+
+#[cfg(test)]
+fn sample(len: usize) -> Vec<u8> {
+    range(0, len).map(|i| (i * 13 + i / 256) as u8).collect()
+}
+
+#[test]
+fn test_manifest() {
+    let data = sample(1000);
+    let manifest = BlockManifest::new(data.as_slice(), 256);
+    assert_eq!(manifest.block_count(), 4);
+    assert_eq!(manifest.block(3), Some(Block {
+        index: 3, offset: 768, len: 232, hash: ::oneshot(data.slice_from(768), 0),
+    }));
+    assert_eq!(manifest.blocks().fold(0, |n, b| n + b.len), 1000);
+
+    let read = BlockManifest::from_reader(&mut BufReader::new(data.as_slice()), 256).unwrap();
+    assert_eq!(read, manifest);
+    let exact = BlockManifest::from_reader(&mut BufReader::new(data.slice_to(512)), 256).unwrap();
+    assert_eq!(exact.block_count(), 2);
+    assert_eq!(BlockManifest::new(&[], 256).block_count(), 0);
+}
+
+#[test]
+fn test_serialization() {
+    let manifest = BlockManifest::new(sample(1000).as_slice(), 256);
+    let bytes = manifest.to_bytes();
+    assert_eq!(bytes.len(), 20 + 4 * 8);
+    assert_eq!(bytes.slice_to(8), b"XXBM\x01\0\0\0".as_slice());
+    assert_eq!(BlockManifest::from_bytes(bytes.as_slice()), Some(manifest));
+
+    assert!(BlockManifest::from_bytes(bytes.slice_to(bytes.len() - 8)).is_none());
+    assert!(BlockManifest::from_bytes(bytes.slice_to(bytes.len() - 1)).is_none());
+    assert!(BlockManifest::from_bytes(bytes.slice_to(10)).is_none());
+}
+
+#[test]
+fn test_diff() {
+    let old = sample(1000);
+    let mut new = old.clone();
+    new[10] ^= 1;
+    new[300] ^= 1;
+    new[600] ^= 1;
+    new.push_all(&[1, 2, 3]);
+
+    let a = BlockManifest::new(old.as_slice(), 256);
+    let b = BlockManifest::new(new.as_slice(), 256);
+    assert!(a.diff(&a).unwrap().is_empty());
+    // blocks 0 to 2 run together; block 3 differs in length only
+    assert_eq!(a.diff(&b).unwrap(), vec![ByteRange { offset: 0, len: 1003 }]);
+
+    new[600] ^= 1;
+    let b = BlockManifest::new(new.slice_to(1000), 256);
+    assert_eq!(a.diff(&b).unwrap(),
+               vec![ByteRange { offset: 0, len: 512 }]);
+    assert_eq!(a.diff(&BlockManifest::new(old.as_slice(), 512)), None);
+}
+
+#[test]
+fn test_missing() {
+    let data = sample(1000);
+    let manifest = BlockManifest::new(data.as_slice(), 256);
+
+    // a preallocated download with blocks 0 and 2 fetched
+    let mut partial: Vec<u8> = repeat(0).take(1000).collect();
+    copy_memory(partial.slice_to_mut(256), data.slice_to(256));
+    copy_memory(partial.slice_mut(512, 768), data.slice(512, 768));
+    assert_eq!(manifest.missing(&mut BufReader::new(partial.as_slice())).unwrap(), vec![1, 3]);
+
+    // a truncated one
+    let got = manifest.missing(&mut BufReader::new(data.slice_to(700))).unwrap();
+    assert_eq!(got, vec![2, 3]);
+    assert!(manifest.missing(&mut BufReader::new(data.as_slice())).unwrap().is_empty());
+}
@@ -0,0 +1,29 @@
+// Little-endian fields in byte buffers, for the file format modules.
+// Callers check the bounds.
+
+use core::prelude::*;
+use core::iter::range;
+
+pub fn le16(b: &[u8], at: usize) -> u16 { #![inline]
+    (b[at] as u16) | (b[at + 1] as u16) << 8
+}
+
+pub fn le32(b: &[u8], at: usize) -> u32 { #![inline]
+    (le16(b, at) as u32) | (le16(b, at + 2) as u32) << 16
+}
+
+pub fn le64(b: &[u8], at: usize) -> u64 { #![inline]
+    (le32(b, at) as u64) | (le32(b, at + 4) as u64) << 32
+}
+
+pub fn put_le32(b: &mut [u8], at: usize, v: u32) { #![inline]
+    for i in range(0, 4) {
+        b[at + i] = (v >> (8 * i)) as u8;
+    }
+}
+
+pub fn put_le64(b: &mut [u8], at: usize, v: u64) { #![inline]
+    for i in range(0, 8) {
+        b[at + i] = (v >> (8 * i)) as u8;
+    }
+}
@@ -0,0 +1,138 @@
+//! Digests with the width in the type, in the reference "canonical"
+//! representation: big-endian bytes, printed as lowercase hex. This is
+//! what `xxhsum` prints and what other implementations store.
+
+use core::prelude::*;
+use core::fmt;
+use core::mem::transmute;
+use core::num::Int;
+use core::str::FromStr;
+
+#[cfg(test)] use std::string::ToString;
+
+macro_rules! digest(($name:ident, $int:ty, $bytes:expr, $hex:expr) => (
+    #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Show)]
+    pub struct $name(pub $int);
+
+    impl $name {
+        pub fn to_be_bytes(&self) -> [u8; $bytes] { #![inline]
+            unsafe { transmute(self.0.to_be()) }
+        }
+
+        pub fn from_be_bytes(bytes: [u8; $bytes]) -> $name { #![inline]
+            $name(Int::from_be(unsafe { transmute::<_, $int>(bytes) }))
+        }
+    }
+
+    impl fmt::String for $name {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, $hex, self.0)
+        }
+    }
+
+    /// Accepts exactly the full number of hex digits, in either case.
+    impl FromStr for $name {
+        fn from_str(s: &str) -> Option<$name> {
+            parse_hex(s, $bytes * 2).map(|v| $name(v as $int))
+        }
+    }
+));
+
+digest!(Xxh32Digest, u32, 4, "{:08x}");
+digest!(Xxh64Digest, u64, 8, "{:016x}");
+
+fn parse_hex(s: &str, digits: usize) -> Option<u64> {
+    if s.len() != digits {
+        return None;
+    }
+
+    let mut value = 0u64;
+    for c in s.bytes() {
+        let nibble = match c {
+            b'0'...b'9' => c - b'0',
+            b'a'...b'f' => c - b'a' + 10,
+            b'A'...b'F' => c - b'A' + 10,
+            _ => return None,
+        };
+        value = (value << 4) | nibble as u64;
+    }
+    Some(value)
+}
+
+// Always encoded as the hex string, in binary formats too. rustc-serialize
+// has no way to ask whether a format is human-readable, so the raw bytes
+// such formats would rather have aren't on offer; the hex string is what a
+// JSON or TOML file would hold anyway.
+#[cfg(feature = "serialize")]
+mod serialize_impls {
+    use core::prelude::*;
+    use core::str::FromStr;
+
+    use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
+    use std::string::ToString;
+
+    use super::{Xxh32Digest, Xxh64Digest};
+
+    macro_rules! serialize_digest(($name:ident) => (
+        impl Encodable for $name {
+            fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+                s.emit_str(self.to_string().as_slice())
+            }
+        }
+
+        impl Decodable for $name {
+            fn decode<D: Decoder>(d: &mut D) -> Result<$name, D::Error> {
+                let hex = try!(d.read_str());
+                match FromStr::from_str(hex.as_slice()) {
+                    Some(digest) => Ok(digest),
+                    None => Err(d.error(concat!("not a canonical ", stringify!($name)))),
+                }
+            }
+        }
+    ));
+
+    serialize_digest!(Xxh32Digest);
+    serialize_digest!(Xxh64Digest);
+}
+
+#[test]
+fn test_canonical_bytes() {
+    let d64 = Xxh64Digest(::oneshot(b"", 0));
+    assert_eq!(d64.to_be_bytes(), [0xef, 0x46, 0xdb, 0x37, 0x51, 0xd8, 0xe9, 0x99]);
+    assert_eq!(Xxh64Digest::from_be_bytes(d64.to_be_bytes()), d64);
+
+    let d32 = Xxh32Digest(::xxh32::oneshot(b"", 0));
+    assert_eq!(d32.to_be_bytes(), [0x02, 0xcc, 0x5d, 0x05]);
+    assert_eq!(Xxh32Digest::from_be_bytes(d32.to_be_bytes()), d32);
+}
+
+#[test]
+fn test_hex() {
+    let d64 = Xxh64Digest(0xef46db3751d8e999);
+    assert_eq!(d64.to_string().as_slice(), "ef46db3751d8e999");
+    assert_eq!("ef46db3751d8e999".parse(), Some(d64));
+    assert_eq!("EF46DB3751D8E999".parse(), Some(d64));
+
+    // leading zeros are part of the canonical form
+    let d32 = Xxh32Digest(0x02cc5d05);
+    assert_eq!(d32.to_string().as_slice(), "02cc5d05");
+    assert_eq!("02cc5d05".parse(), Some(d32));
+
+    assert_eq!("2cc5d05".parse::<Xxh32Digest>(), None);
+    assert_eq!("02cc5d05".parse::<Xxh64Digest>(), None);
+    assert_eq!("02cc5d0g".parse::<Xxh32Digest>(), None);
+    assert_eq!("+2cc5d05".parse::<Xxh32Digest>(), None);
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn test_serialize() {
+    use rustc_serialize::json;
+
+    let d64 = Xxh64Digest(0xef46db3751d8e999);
+    assert_eq!(json::encode(&d64).as_slice(), "\"ef46db3751d8e999\"");
+    assert_eq!(json::decode::<Xxh64Digest>("\"ef46db3751d8e999\"").unwrap(), d64);
+    assert_eq!(json::decode::<Xxh32Digest>("\"02cc5d05\"").unwrap(), Xxh32Digest(0x02cc5d05));
+    assert!(json::decode::<Xxh32Digest>("\"ef46db3751d8e999\"").is_err());
+    assert!(json::decode::<Xxh64Digest>("42").is_err());
+}
@@ -0,0 +1,295 @@
+//! Content-defined chunking, FastCDC style, with an xxh64 for each chunk.
+//!
+//! A gear hash rolls over the input and a chunk ends where its top bits
+//! are all zero, so boundaries follow the content and an insert only
+//! disturbs the chunks around it. Normalized chunking uses a stricter mask
+//! before the average size and a looser one after, which keeps sizes
+//! close to the average. No boundary falls before the minimum size and
+//! every chunk ends by the maximum.
+//!
+//! The gear table is derived from xxh64 rather than a random source, so
+//! boundaries are the same on every platform. Chunk digests are xxh64,
+//! seed 0; the crate has no XXH128.
+
+use core::prelude::*;
+use core::cmp::min;
+use core::num::Int;
+
+#[cfg(feature = "std")] use core::iter::repeat;
+#[cfg(feature = "std")] use std::io::{Reader, IoResult};
+#[cfg(any(feature = "std", test))] use std::vec::Vec;
+
+#[cfg(feature = "std")] use io::read_full;
+
+#[cfg(any(feature = "std", test))] use core::iter::range;
+#[cfg(all(test, feature = "std"))] use std::io::BufReader;
+
+/// "gear"
+pub const GEAR_SEED: u64 = 0x67656172;
+
+/// The gear table entry for byte `b`.
+pub fn gear(b: u8) -> u64 { #![inline]
+    ::oneshot(&[b], GEAR_SEED)
+}
+
+/// One chunk of the input.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct Chunk {
+    pub offset: u64,
+    pub len: usize,
+    pub hash: u64,
+}
+
+#[derive(Copy)]
+pub struct Chunker {
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask_small: u64,
+    mask_large: u64,
+    gear: [u64; 256],
+}
+
+impl Chunker {
+    /// `None` unless `0 < min <= avg <= max` and `avg` is a power of two
+    /// from 64 to 1 GiB.
+    pub fn new(min: usize, avg: usize, max: usize) -> Option<Chunker> {
+        if min == 0 || min > avg || avg > max
+            || avg < 64 || avg > 1 << 30 || avg & (avg - 1) != 0 {
+            return None;
+        }
+        let bits = avg.trailing_zeros();
+        // the top `n` bits, which have seen the most of the window
+        let top = |n: usize| ((1u64 << n) - 1) << (64 - n);
+
+        let mut chunker = Chunker {
+            min: min,
+            avg: avg,
+            max: max,
+            mask_small: top(bits + 2),
+            mask_large: top(bits - 2),
+            gear: [0; 256],
+        };
+        for (i, g) in chunker.gear.iter_mut().enumerate() {
+            *g = gear(i as u8);
+        }
+        Some(chunker)
+    }
+
+    /// 2 KiB minimum, 8 KiB average, 64 KiB maximum.
+    pub fn default_sizes() -> Chunker {
+        Chunker::new(2 * 1024, 8 * 1024, 64 * 1024).unwrap()
+    }
+
+    pub fn min_size(&self) -> usize { #![inline] self.min }
+    pub fn avg_size(&self) -> usize { #![inline] self.avg }
+    pub fn max_size(&self) -> usize { #![inline] self.max }
+
+    /// The length of the chunk `data` starts with. Only final when `data`
+    /// holds at least `max_size` bytes or is the end of the input.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min {
+            return data.len();
+        }
+        let end = min(data.len(), self.max);
+        let normal = min(end, self.avg);
+
+        let mut hash = 0u64;
+        let mut i = self.min;
+        while i < normal {
+            hash = (hash << 1) + self.gear[data[i] as usize];
+            if hash & self.mask_small == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            hash = (hash << 1) + self.gear[data[i] as usize];
+            if hash & self.mask_large == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        end
+    }
+
+    /// The chunks of `data`, all in memory.
+    pub fn chunks<'a>(&'a self, data: &'a [u8]) -> Chunks<'a> { #![inline]
+        Chunks { chunker: self, data: data, offset: 0 }
+    }
+
+    /// The chunks of everything `reader` produces, each with its bytes.
+    /// One buffer of twice the maximum chunk size is reused throughout,
+    /// and each chunk's bytes are copied out into a `Vec` of their own.
+    #[cfg(feature = "std")]
+    pub fn chunks_reader<'a, R: Reader>(&'a self, reader: R) -> ReaderChunks<'a, R> { #![inline]
+        ReaderChunks { chunker: self, reader: reader, buf: Vec::new(), start: 0, offset: 0, eof: false }
+    }
+}
+
+impl Clone for Chunker {
+    fn clone(&self) -> Chunker { #![inline]
+        *self
+    }
+}
+
+pub struct Chunks<'a> {
+    chunker: &'a Chunker,
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Chunk> {
+        let rest = self.data.slice_from(self.offset);
+        if rest.is_empty() {
+            return None;
+        }
+        let len = self.chunker.cut(rest);
+        let chunk = Chunk { offset: self.offset as u64, len: len, hash: ::oneshot(rest.slice_to(len), 0) };
+        self.offset += len;
+        Some(chunk)
+    }
+}
+
+#[cfg(feature = "std")]
+pub struct ReaderChunks<'a, R> {
+    chunker: &'a Chunker,
+    reader: R,
+    buf: Vec<u8>,
+    // where the unchunked bytes in `buf` start
+    start: usize,
+    offset: u64,
+    eof: bool,
+}
+
+#[cfg(feature = "std")]
+impl<'a, R: Reader> ReaderChunks<'a, R> {
+    fn next_chunk(&mut self) -> IoResult<Option<(Chunk, Vec<u8>)>> {
+        let max = self.chunker.max;
+        if !self.eof && self.buf.len() - self.start < max {
+            // move what's left to the front and fill up to twice the
+            // maximum, which reads at least as much as it moves
+            let left = self.buf.len() - self.start;
+            for i in range(0, left) {
+                self.buf[i] = self.buf[self.start + i];
+            }
+            self.buf.truncate(left);
+            self.start = 0;
+            self.buf.extend(repeat(0).take(2 * max - left));
+            let n = match read_full(&mut self.reader, self.buf.slice_from_mut(left)) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.buf.truncate(left);
+                    return Err(e);
+                }
+            };
+            self.buf.truncate(left + n);
+            self.eof = left + n < 2 * max;
+        }
+
+        let (len, bytes) = {
+            let rest = self.buf.slice_from(self.start);
+            if rest.is_empty() {
+                return Ok(None);
+            }
+            let len = self.chunker.cut(rest);
+            let mut bytes = Vec::with_capacity(len);
+            bytes.push_all(rest.slice_to(len));
+            (len, bytes)
+        };
+        self.start += len;
+
+        let chunk = Chunk { offset: self.offset, len: len, hash: ::oneshot(bytes.as_slice(), 0) };
+        self.offset += len as u64;
+        Ok(Some((chunk, bytes)))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, R: Reader> Iterator for ReaderChunks<'a, R> {
+    type Item = IoResult<(Chunk, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<IoResult<(Chunk, Vec<u8>)>> {
+        match self.next_chunk() {
+            Ok(Some(chunk)) => Some(Ok(chunk)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+fn sample(len: usize) -> Vec<u8> {
+    let mut x = 1u64;
+    range(0, len).map(|_| {
+        x = x * 6364136223846793005 + 1442695040888963407;
+        (x >> 56) as u8
+    }).collect()
+}
+
+#[test]
+fn test_boundaries() {
+    // the same on every platform, as computed by an independent model
+    assert_eq!(gear(0), 0x7518d291af360b59);
+    assert_eq!(gear(255), 0xaadc9a3f80fa5d8f);
+
+    let data = sample(64 * 1024);
+    let chunker = Chunker::new(256, 1024, 4096).unwrap();
+    let chunks: Vec<Chunk> = chunker.chunks(data.as_slice()).collect();
+    assert_eq!(chunks.len(), 60);
+    assert_eq!(chunks.slice_to(4), [
+        Chunk { offset: 0, len: 1131, hash: 0x190dc493eaf9d02e },
+        Chunk { offset: 1131, len: 1069, hash: 0x5319a7093e303abc },
+        Chunk { offset: 2200, len: 700, hash: 0xbb8e4c832e7b8d96 },
+        Chunk { offset: 2900, len: 1065, hash: 0x6af2d0c75f3ce329 },
+    ].as_slice());
+
+    let mut end = 0;
+    for chunk in chunks.iter() {
+        assert_eq!(chunk.offset, end);
+        assert!(chunk.len <= 4096);
+        end += chunk.len as u64;
+    }
+    assert_eq!(end, data.len() as u64);
+    assert!(chunks.init().iter().all(|c| c.len >= 256));
+
+    assert!(Chunker::new(512, 1000, 4096).is_none());
+    assert!(Chunker::new(2048, 1024, 4096).is_none());
+}
+
+#[test]
+fn test_insert_shifts_little() {
+    let data = sample(64 * 1024);
+    let mut edited = Vec::new();
+    edited.push_all(data.slice_to(5000));
+    edited.push_all(b"0123456789");
+    edited.push_all(data.slice_from(5000));
+
+    let chunker = Chunker::new(256, 1024, 4096).unwrap();
+    let before: Vec<u64> = chunker.chunks(data.as_slice()).map(|c| c.hash).collect();
+    let after: Vec<u64> = chunker.chunks(edited.as_slice()).map(|c| c.hash).collect();
+    let kept = before.iter().filter(|h| after.contains(*h)).count();
+    assert_eq!(kept, 59);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_reader_matches_slices() {
+    let data = sample(64 * 1024 + 123);
+    for &(min, avg, max) in [(256, 1024, 4096), (64, 64, 64), (1, 512, 100000)].iter() {
+        let chunker = Chunker::new(min, avg, max).unwrap();
+        let expected: Vec<Chunk> = chunker.chunks(data.as_slice()).collect();
+        let mut got = Vec::new();
+        for result in chunker.chunks_reader(BufReader::new(data.as_slice())) {
+            let (chunk, bytes) = result.unwrap();
+            assert_eq!(bytes.as_slice(), data.slice(chunk.offset as usize, chunk.offset as usize + chunk.len));
+            assert_eq!(bytes.capacity(), chunk.len);
+            got.push(chunk);
+        }
+        assert_eq!(got, expected);
+    }
+    assert!(Chunker::default_sizes().chunks_reader(BufReader::new(&[])).next().is_none());
+}
@@ -0,0 +1,39 @@
+//! Hashes of string literals as real constants, for build scripts.
+//!
+//! `xxh64!` and `xxh32!` hash at run time, since the compiler can't
+//! evaluate a hash on its own. Where the value has to be a constant, say
+//! in a `static` or a `match` arm, generate it from `build.rs` instead:
+//!
+//! ```ignore
+//! // build.rs
+//! let path = Path::new(os::getenv("OUT_DIR").unwrap()).join("ids.rs");
+//! let mut out = File::create(&path).unwrap();
+//! codegen::write_xxh64_consts(&mut out, 0, &[("PING", "Message::Ping")]).unwrap();
+//!
+//! // the crate
+//! include!(concat!(env!("OUT_DIR"), "/ids.rs"));
+//! ```
+//!
+//! Each constant is the same value `xxh64!(literal, seed)` returns.
+
+use core::prelude::*;
+
+use std::io::{Writer, IoResult};
+
+/// `pub const NAME: u64 = ...;` for each `(NAME, literal)` pair.
+pub fn write_xxh64_consts<W: Writer>(out: &mut W, seed: u64, consts: &[(&str, &str)]) -> IoResult<()> {
+    for &(name, literal) in consts.iter() {
+        try!(writeln!(out, "/// xxh64 of {:?}, seed {}", literal, seed));
+        try!(writeln!(out, "pub const {}: u64 = 0x{:016x};", name, ::oneshot(literal.as_bytes(), seed)));
+    }
+    Ok(())
+}
+
+/// `pub const NAME: u32 = ...;` for each `(NAME, literal)` pair.
+pub fn write_xxh32_consts<W: Writer>(out: &mut W, seed: u32, consts: &[(&str, &str)]) -> IoResult<()> {
+    for &(name, literal) in consts.iter() {
+        try!(writeln!(out, "/// xxh32 of {:?}, seed {}", literal, seed));
+        try!(writeln!(out, "pub const {}: u32 = 0x{:08x};", name, ::xxh32::oneshot(literal.as_bytes(), seed)));
+    }
+    Ok(())
+}
@@ -0,0 +1,595 @@
+//! rsync-style deltas: a signature of the base file, a scan of the new
+//! file against it, and the copy and insert instructions that rebuild the
+//! new file from the base.
+//!
+//! The signature holds, per full block of the base, rsync's weak rolling
+//! checksum and a strong xxh64 (seed 0). The scan slides the weak checksum
+//! over the new file one byte at a time and only hashes a window with
+//! xxh64 when the weak one matches. A short last block of the base is
+//! never matched; its bytes travel as inserts.
+//!
+//! Each delta carries the xxh64 of the whole new file, which `apply`
+//! checks, so a delta applied to the wrong base fails instead of silently
+//! producing garbage.
+//!
+//! Signatures and deltas can be made from readers as well as slices, and
+//! both have a serialized form for sending them across:
+//!
+//!     "XXSG" | version u8 | 3 zero bytes | block size u32 LE |
+//!     (weak u32 LE | xxh64 u64 LE) per block
+//!
+//!     "XXDL" | version u8 | 3 zero bytes | new length u64 LE |
+//!     xxh64 u64 LE | ops
+//!
+//! where a copy is `0 | offset u64 LE | length u64 LE` and an insert is
+//! `1 | length u64 LE | bytes`.
+
+use core::prelude::*;
+use core::cmp::{min, max};
+use core::fmt;
+use core::hash::{Hasher, Writer};
+use core::iter::{range, repeat};
+use core::num::Int;
+use core::slice::bytes::copy_memory;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::{Reader, IoResult};
+use std::vec::Vec;
+
+use bytes::{le32, le64, put_le32, put_le64};
+use io::read_full;
+use XXHasher;
+
+#[cfg(test)] use std::io::BufReader;
+
+const SIGNATURE_MAGIC: &'static [u8] = b"XXSG";
+const DELTA_MAGIC: &'static [u8] = b"XXDL";
+
+pub const VERSION: u8 = 1;
+
+const SIGNATURE_HEADER_LEN: usize = 12;
+const DELTA_HEADER_LEN: usize = 24;
+
+const COPY: u8 = 0;
+const INSERT: u8 = 1;
+
+// how much of the new file `delta_reader` reads at a time
+const READ_BYTES: usize = 64 * 1024;
+
+/// rsync's weak checksum: two 16-bit sums over a window, which can roll
+/// forward one byte at a time.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    pub fn new(window: &[u8]) -> Rolling {
+        let len = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, x) in window.iter().enumerate() {
+            a = (a + *x as u32) & 0xffff;
+            b = (b + (len - i as u32) * *x as u32) & 0xffff;
+        }
+        Rolling { a: a, b: b, len: len }
+    }
+
+    /// Slide the window one byte: `out` leaves at the front, `in_` enters
+    /// at the back.
+    pub fn roll(&mut self, out: u8, in_: u8) { #![inline]
+        self.a = (self.a + 0x10000 - out as u32 + in_ as u32) & 0xffff;
+        let dropped = (self.len * out as u32) & 0xffff;
+        self.b = (self.b + 0x10000 - dropped + self.a) & 0xffff;
+    }
+
+    pub fn digest(&self) -> u32 { #![inline]
+        self.a | self.b << 16
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: u64,
+}
+
+impl BlockSignature {
+    fn of(block: &[u8]) -> BlockSignature { #![inline]
+        BlockSignature { weak: Rolling::new(block).digest(), strong: ::oneshot(block, 0) }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct Signature {
+    pub block_size: usize,
+    /// One per full block of the base file.
+    pub blocks: Vec<BlockSignature>,
+}
+
+impl Signature {
+    /// Panics if `block_size` is zero.
+    pub fn new(base: &[u8], block_size: usize) -> Signature {
+        assert!(block_size > 0);
+        let blocks = base.chunks(block_size)
+            .filter(|block| block.len() == block_size)
+            .map(|block| BlockSignature::of(block))
+            .collect();
+        Signature { block_size: block_size, blocks: blocks }
+    }
+
+    /// Panics if `block_size` is zero.
+    pub fn from_reader<R: Reader>(reader: &mut R, block_size: usize) -> IoResult<Signature> {
+        assert!(block_size > 0);
+        let mut buf: Vec<u8> = repeat(0).take(block_size).collect();
+        let mut signature = Signature { block_size: block_size, blocks: Vec::new() };
+        loop {
+            let n = try!(read_full(reader, buf.as_mut_slice()));
+            if n < block_size {
+                return Ok(signature);
+            }
+            signature.blocks.push(BlockSignature::of(buf.as_slice()));
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = repeat(0).take(SIGNATURE_HEADER_LEN + 12 * self.blocks.len()).collect();
+        copy_memory(out.slice_to_mut(4), SIGNATURE_MAGIC);
+        out[4] = VERSION;
+        put_le32(out.as_mut_slice(), 8, self.block_size as u32);
+        for (i, block) in self.blocks.iter().enumerate() {
+            let at = SIGNATURE_HEADER_LEN + 12 * i;
+            put_le32(out.as_mut_slice(), at, block.weak);
+            put_le64(out.as_mut_slice(), at + 4, block.strong);
+        }
+        out
+    }
+
+    /// `None` unless `bytes` is a whole signature.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Signature> {
+        if bytes.len() < SIGNATURE_HEADER_LEN || bytes.slice_to(4) != SIGNATURE_MAGIC
+            || bytes[4] != VERSION || bytes.slice(5, 8) != [0u8, 0, 0].as_slice() {
+            return None;
+        }
+        let block_size = le32(bytes, 8) as usize;
+        if block_size == 0 || (bytes.len() - SIGNATURE_HEADER_LEN) % 12 != 0 {
+            return None;
+        }
+        let blocks = range(0, (bytes.len() - SIGNATURE_HEADER_LEN) / 12).map(|i| {
+            let at = SIGNATURE_HEADER_LEN + 12 * i;
+            BlockSignature { weak: le32(bytes, at), strong: le64(bytes, at + 4) }
+        }).collect();
+        Some(Signature { block_size: block_size, blocks: blocks })
+    }
+}
+
+// Weak checksum to the blocks that have it, first occurrence first.
+struct Index<'a> {
+    signature: &'a Signature,
+    blocks: HashMap<u32, Vec<usize>>,
+}
+
+impl<'a> Index<'a> {
+    fn new(signature: &'a Signature) -> Index<'a> {
+        let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, block) in signature.blocks.iter().enumerate() {
+            match blocks.entry(block.weak) {
+                Entry::Occupied(mut e) => e.get_mut().push(i),
+                Entry::Vacant(e) => { e.insert(vec![i]); }
+            }
+        }
+        Index { signature: signature, blocks: blocks }
+    }
+
+    // The block `window` is a copy of, if any.
+    fn find(&self, weak: u32, window: &[u8]) -> Option<usize> {
+        let candidates = match self.blocks.get(&weak) { Some(c) => c, None => return None };
+        let strong = ::oneshot(window, 0);
+        candidates.iter().map(|i| *i).find(|i| self.signature.blocks[*i].strong == strong)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub enum Op {
+    /// Bytes from the base file.
+    Copy { offset: u64, len: u64 },
+    /// Literal bytes.
+    Insert(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct Delta {
+    pub ops: Vec<Op>,
+    /// Length and xxh64 of the file `ops` produce.
+    pub len: u64,
+    pub hash: u64,
+}
+
+impl Delta {
+    /// Bytes that have to travel literally.
+    pub fn literal_len(&self) -> u64 {
+        self.ops.iter().fold(0, |n, op| match *op {
+            Op::Insert(ref bytes) => n + bytes.len() as u64,
+            Op::Copy { .. } => n,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = self.ops.iter().fold(DELTA_HEADER_LEN, |n, op| match *op {
+            Op::Copy { .. } => n + 17,
+            Op::Insert(ref bytes) => n + 9 + bytes.len(),
+        });
+        let mut out: Vec<u8> = repeat(0).take(len).collect();
+        copy_memory(out.slice_to_mut(4), DELTA_MAGIC);
+        out[4] = VERSION;
+        put_le64(out.as_mut_slice(), 8, self.len);
+        put_le64(out.as_mut_slice(), 16, self.hash);
+        let mut at = DELTA_HEADER_LEN;
+        for op in self.ops.iter() {
+            match *op {
+                Op::Copy { offset, len } => {
+                    out[at] = COPY;
+                    put_le64(out.as_mut_slice(), at + 1, offset);
+                    put_le64(out.as_mut_slice(), at + 9, len);
+                    at += 17;
+                }
+                Op::Insert(ref bytes) => {
+                    out[at] = INSERT;
+                    put_le64(out.as_mut_slice(), at + 1, bytes.len() as u64);
+                    copy_memory(out.slice_from_mut(at + 9), bytes.as_slice());
+                    at += 9 + bytes.len();
+                }
+            }
+        }
+        out
+    }
+
+    /// `None` unless `bytes` is a whole delta. The ops aren't checked
+    /// against any base; `apply` does that.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Delta> {
+        if bytes.len() < DELTA_HEADER_LEN || bytes.slice_to(4) != DELTA_MAGIC
+            || bytes[4] != VERSION || bytes.slice(5, 8) != [0u8, 0, 0].as_slice() {
+            return None;
+        }
+        let mut delta = Delta { ops: Vec::new(), len: le64(bytes, 8), hash: le64(bytes, 16) };
+        let mut at = DELTA_HEADER_LEN;
+        while at < bytes.len() {
+            if bytes.len() - at < 9 {
+                return None;
+            }
+            match bytes[at] {
+                COPY => {
+                    if bytes.len() - at < 17 {
+                        return None;
+                    }
+                    delta.ops.push(Op::Copy { offset: le64(bytes, at + 1), len: le64(bytes, at + 9) });
+                    at += 17;
+                }
+                INSERT => {
+                    let len = le64(bytes, at + 1);
+                    if len > (bytes.len() - at - 9) as u64 {
+                        return None;
+                    }
+                    let len = len as usize;
+                    let mut literal = Vec::with_capacity(len);
+                    literal.push_all(bytes.slice(at + 9, at + 9 + len));
+                    delta.ops.push(Op::Insert(literal));
+                    at += 9 + len;
+                }
+                _ => return None,
+            }
+        }
+        Some(delta)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum Error {
+    /// A copy reaches past the end of the base file.
+    OutOfRange { offset: u64, len: u64 },
+    /// The result isn't the file the delta was made for.
+    Mismatch { expected: u64, actual: u64 },
+}
+
+impl fmt::String for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::OutOfRange { offset, len } =>
+                write!(f, "copy of {} bytes at {} is past the end of the base", len, offset),
+            Error::Mismatch { expected, actual } =>
+                write!(f, "rebuilt file hashes to {:016x}, expected {:016x}", actual, expected),
+        }
+    }
+}
+
+/// The instructions that turn the base file `signature` describes into
+/// `new`.
+pub fn delta(signature: &Signature, new: &[u8]) -> Delta {
+    let bs = signature.block_size;
+    let index = Index::new(signature);
+
+    let mut ops = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    let mut rolling = if new.len() >= bs { Some(Rolling::new(new.slice_to(bs))) } else { None };
+    while let Some(mut weak) = rolling {
+        match index.find(weak.digest(), new.slice(i, i + bs)) {
+            Some(block) => {
+                push_insert(&mut ops, new.slice(literal_start, i));
+                push_copy(&mut ops, (block * bs) as u64, bs as u64);
+                i += bs;
+                literal_start = i;
+                rolling = if i + bs <= new.len() { Some(Rolling::new(new.slice(i, i + bs))) } else { None };
+            }
+            None => {
+                if i + bs < new.len() {
+                    weak.roll(new[i], new[i + bs]);
+                    rolling = Some(weak);
+                } else {
+                    rolling = None;
+                }
+                i += 1;
+            }
+        }
+    }
+    push_insert(&mut ops, new.slice_from(literal_start));
+
+    Delta { ops: ops, len: new.len() as u64, hash: ::oneshot(new, 0) }
+}
+
+/// The same delta as `delta`, for a new file read from `reader`. Apart from
+/// the delta itself, no more than two reads of the file are held at once,
+/// each 64 KiB or a block, whichever is larger.
+pub fn delta_reader<R: Reader>(signature: &Signature, reader: &mut R) -> IoResult<Delta> {
+    let bs = signature.block_size;
+    let index = Index::new(signature);
+    let read_size = max(bs, READ_BYTES);
+
+    let mut ops = Vec::new();
+    let mut hasher = XXHasher::new_with_seed(0);
+    let mut len = 0u64;
+    // buf[start..pos] is literal, buf[pos..pos + bs] the window
+    let mut buf: Vec<u8> = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        if pos + bs > buf.len() {
+            if eof {
+                break;
+            }
+            // hand the literal over before it piles up, then drop what's
+            // been dealt with and read on
+            if pos - start >= read_size {
+                push_insert(&mut ops, buf.slice(start, pos));
+                start = pos;
+            }
+            let keep = buf.len() - start;
+            for i in range(0, keep) {
+                buf[i] = buf[start + i];
+            }
+            buf.truncate(keep);
+            pos -= start;
+            start = 0;
+            buf.extend(repeat(0).take(read_size));
+            let n = try!(read_full(reader, buf.slice_from_mut(keep)));
+            buf.truncate(keep + n);
+            hasher.write(buf.slice_from(keep));
+            len += n as u64;
+            eof = n < read_size;
+            continue;
+        }
+
+        let mut weak = match rolling { Some(weak) => weak, None => Rolling::new(buf.slice(pos, pos + bs)) };
+        match index.find(weak.digest(), buf.slice(pos, pos + bs)) {
+            Some(block) => {
+                push_insert(&mut ops, buf.slice(start, pos));
+                push_copy(&mut ops, (block * bs) as u64, bs as u64);
+                pos += bs;
+                start = pos;
+                rolling = None;
+            }
+            None => {
+                // past the end of what's read the window starts over
+                if pos + bs < buf.len() {
+                    weak.roll(buf[pos], buf[pos + bs]);
+                    rolling = Some(weak);
+                } else {
+                    rolling = None;
+                }
+                pos += 1;
+            }
+        }
+    }
+    push_insert(&mut ops, buf.slice_from(start));
+
+    Ok(Delta { ops: ops, len: len, hash: hasher.finish() })
+}
+
+// Adjacent inserts become one, so a literal handed over in parts is still
+// a single op.
+fn push_insert(ops: &mut Vec<Op>, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    if let Some(&mut Op::Insert(ref mut literal)) = ops.last_mut() {
+        literal.push_all(bytes);
+        return;
+    }
+    let mut literal = Vec::with_capacity(bytes.len());
+    literal.push_all(bytes);
+    ops.push(Op::Insert(literal));
+}
+
+// Adjacent copies of adjacent base blocks become one.
+fn push_copy(ops: &mut Vec<Op>, offset: u64, len: u64) {
+    if let Some(last) = ops.last_mut() {
+        if let Op::Copy { offset: prev_offset, len: ref mut prev_len } = *last {
+            if prev_offset + *prev_len == offset {
+                *prev_len += len;
+                return;
+            }
+        }
+    }
+    ops.push(Op::Copy { offset: offset, len: len });
+}
+
+/// Rebuild the new file from `base` and check it against the delta.
+pub fn apply(base: &[u8], delta: &Delta) -> Result<Vec<u8>, Error> {
+    // `delta.len` may come off the wire, so it only gets to lower the
+    // guess; copies can still outgrow the base, which the `Vec` handles
+    let mut out = Vec::with_capacity(min(delta.len, base.len() as u64 + delta.literal_len()) as usize);
+    for op in delta.ops.iter() {
+        match *op {
+            Op::Copy { offset, len } => {
+                match offset.checked_add(len) {
+                    Some(end) if end <= base.len() as u64 => {}
+                    _ => return Err(Error::OutOfRange { offset: offset, len: len }),
+                }
+                out.push_all(base.slice(offset as usize, (offset + len) as usize));
+            }
+            Op::Insert(ref bytes) => out.push_all(bytes.as_slice()),
+        }
+    }
+    let actual = ::oneshot(out.as_slice(), 0);
+    if out.len() as u64 != delta.len || actual != delta.hash {
+        return Err(Error::Mismatch { expected: delta.hash, actual: actual });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+fn sample(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed;
+    range(0, len).map(|_| {
+        x = x * 6364136223846793005 + 1442695040888963407;
+        (x >> 56) as u8
+    }).collect()
+}
+
+#[test]
+fn test_rolling() {
+    let data = sample(300, 1);
+    let mut rolling = Rolling::new(data.slice_to(64));
+    for i in range(1, data.len() - 64 + 1) {
+        rolling.roll(data[i - 1], data[i + 63]);
+        assert_eq!(rolling, Rolling::new(data.slice(i, i + 64)));
+    }
+    // rsync's definition, by hand
+    assert_eq!(Rolling::new(&[1, 2, 3]).digest(), (1 + 2 + 3) | (3 * 1 + 2 * 2 + 1 * 3) << 16);
+}
+
+#[test]
+fn test_identical() {
+    let base = sample(10 * 512 + 100, 1);
+    let signature = Signature::new(base.as_slice(), 512);
+    assert_eq!(signature.blocks.len(), 10);
+
+    let d = delta(&signature, base.as_slice());
+    // the short last block goes literally
+    assert_eq!(d.ops.len(), 2);
+    assert_eq!(d.ops[0], Op::Copy { offset: 0, len: 10 * 512 });
+    assert_eq!(d.literal_len(), 100);
+    assert_eq!(apply(base.as_slice(), &d).unwrap(), base);
+}
+
+#[test]
+fn test_edits() {
+    let base = sample(20 * 256, 1);
+    let mut new = Vec::new();
+    new.push_all(b"header");
+    new.push_all(base.slice(0, 5 * 256 + 17));
+    new.push_all(b"inserted in the middle");
+    new.push_all(base.slice(5 * 256 + 17, 15 * 256));
+    new.push_all(base.slice(16 * 256, 20 * 256));
+
+    let signature = Signature::new(base.as_slice(), 256);
+    let d = delta(&signature, new.as_slice());
+    assert_eq!(apply(base.as_slice(), &d).unwrap(), new);
+    // only the edits and the block they landed in go literally
+    assert!(d.literal_len() < 2 * 256 + 100);
+    assert!(d.ops.contains(&Op::Copy { offset: 16 * 256, len: 4 * 256 }));
+
+    // nothing in common
+    let other = sample(1000, 2);
+    let d = delta(&signature, other.as_slice());
+    assert_eq!(d.literal_len(), 1000);
+    assert_eq!(apply(base.as_slice(), &d).unwrap(), other);
+    assert_eq!(delta(&signature, &[]).ops.len(), 0);
+}
+
+#[test]
+fn test_wrong_base() {
+    let base = sample(4 * 256, 1);
+    let d = delta(&Signature::new(base.as_slice(), 256), base.as_slice());
+
+    let mut changed = base.clone();
+    changed[3] ^= 1;
+    match apply(changed.as_slice(), &d) {
+        Err(Error::Mismatch { expected, .. }) => assert_eq!(expected, ::oneshot(base.as_slice(), 0)),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(apply(base.slice_to(512), &d), Err(Error::OutOfRange { offset: 0, len: 1024 }));
+
+    // as a delta off the wire might claim
+    let huge = Delta { ops: vec![Op::Copy { offset: Int::max_value(), len: 2 }], len: Int::max_value(), hash: 0 };
+    assert_eq!(apply(base.as_slice(), &huge), Err(Error::OutOfRange { offset: Int::max_value(), len: 2 }));
+    let huge = Delta { ops: vec![Op::Insert(vec![1, 2, 3])], len: Int::max_value(), hash: 0 };
+    assert!(apply(base.as_slice(), &huge).is_err());
+}
+
+#[test]
+fn test_reader_matches_slices() {
+    let base = sample(300 * 256, 1);
+    let mut new = Vec::new();
+    new.push_all(base.slice(0, 100 * 256 + 3));
+    // longer than a read, so the literal goes over in parts
+    new.push_all(sample(3 * READ_BYTES + 10, 2).as_slice());
+    new.push_all(base.slice(150 * 256, 300 * 256));
+
+    for &bs in [256, 1000, 2 * READ_BYTES].iter() {
+        let signature = Signature::new(base.as_slice(), bs);
+        assert_eq!(Signature::from_reader(&mut BufReader::new(base.as_slice()), bs).unwrap(), signature);
+        for data in [new.as_slice(), base.as_slice(), base.slice_to(255), base.slice_to(0)].iter() {
+            let d = delta_reader(&signature, &mut BufReader::new(*data)).unwrap();
+            assert_eq!(d, delta(&signature, *data));
+        }
+    }
+}
+
+#[test]
+fn test_serialization() {
+    let base = sample(20 * 256, 1);
+    let mut new = Vec::new();
+    new.push_all(b"header");
+    new.push_all(base.slice(3 * 256, 20 * 256));
+    new.push_all(b"trailer");
+
+    let signature = Signature::new(base.as_slice(), 256);
+    let bytes = signature.to_bytes();
+    assert_eq!(bytes.len(), 12 + 20 * 12);
+    assert_eq!(bytes.slice_to(12), b"XXSG\x01\0\0\0\x00\x01\0\0".as_slice());
+    assert_eq!(Signature::from_bytes(bytes.as_slice()), Some(signature.clone()));
+    assert!(Signature::from_bytes(bytes.slice_to(bytes.len() - 1)).is_none());
+    assert!(Signature::from_bytes(b"XXSG\x01\0\0\0\0\0\0\0").is_none());
+
+    let d = delta(&signature, new.as_slice());
+    assert_eq!(d.ops.len(), 3);
+    let bytes = d.to_bytes();
+    assert_eq!(bytes.len(), 24 + (9 + 6) + 17 + (9 + 7));
+    let decoded = Delta::from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded, d);
+    assert_eq!(apply(base.as_slice(), &decoded).unwrap(), new);
+
+    assert!(Delta::from_bytes(bytes.slice_to(bytes.len() - 1)).is_none());
+    assert!(Delta::from_bytes(bytes.slice_to(30)).is_none());
+    let mut bad_op = bytes.clone();
+    bad_op[24] = 2;
+    assert!(Delta::from_bytes(bad_op.as_slice()).is_none());
+    let empty = Delta { ops: Vec::new(), len: 0, hash: ::oneshot(b"", 0) };
+    assert_eq!(Delta::from_bytes(empty.to_bytes().as_slice()), Some(empty));
+}
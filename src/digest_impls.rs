@@ -0,0 +1,86 @@
+//! rust-crypto's `Digest` for both hashers, so they can stand in wherever
+//! a `crypto::digest::Digest` is expected. The output is the canonical
+//! big-endian digest, and `Digest::reset` is the hasher's own `reset`,
+//! which keeps the seed.
+//!
+//! The digests other xxHash tools produce are those of seed 0, so build
+//! the hasher with `new_with_seed(0)`. The 64-bit `new()` and `Default`
+//! use a different seed and give digests nothing else will reproduce.
+
+use core::prelude::*;
+use core::hash::{Hasher, Writer};
+use core::slice::bytes::copy_memory;
+
+use crypto::digest::Digest;
+
+use canonical::{Xxh32Digest, Xxh64Digest};
+use XXHasher;
+use xxh32;
+
+
+macro_rules! impl_digest(($hasher:ty, $canonical:ident, $bits:expr, $block:expr) => (
+    impl Digest for $hasher {
+        fn input(&mut self, input: &[u8]) { #![inline]
+            Writer::write(self, input)
+        }
+
+        /// `out` has to hold the whole digest.
+        fn result(&mut self, out: &mut [u8]) {
+            let bytes = $canonical(Hasher::finish(self)).to_be_bytes();
+            copy_memory(out, &bytes);
+        }
+
+        fn reset(&mut self) { #![inline]
+            Hasher::reset(self)
+        }
+
+        fn output_bits(&self) -> usize { #![inline]
+            $bits
+        }
+
+        fn block_size(&self) -> usize { #![inline]
+            $block
+        }
+    }
+));
+
+// the block size is a stripe: one round over all four lanes
+impl_digest!(XXHasher, Xxh64Digest, 64, 32);
+impl_digest!(xxh32::XXHasher, Xxh32Digest, 32, 16);
+
+#[test]
+fn test_digest_xxh64() {
+    let mut state = XXHasher::new_with_seed(0);
+    Digest::input_str(&mut state, "");
+    assert_eq!(Digest::result_str(&mut state).as_slice(), "ef46db3751d8e999");
+
+    Digest::reset(&mut state);
+    Digest::input(&mut state, b"Nobody inspects");
+    Digest::input(&mut state, b" the spammish repetition");
+    let mut out = [0u8; 8];
+    Digest::result(&mut state, &mut out);
+    let expected = Xxh64Digest(::oneshot(b"Nobody inspects the spammish repetition", 0));
+    assert_eq!(out, expected.to_be_bytes());
+    assert_eq!(state.output_bytes(), 8);
+
+    // a seeded hasher stays seeded through a reset
+    let mut seeded = XXHasher::new_with_seed(7);
+    Digest::input(&mut seeded, b"abc");
+    Digest::reset(&mut seeded);
+    Digest::input(&mut seeded, b"xyz");
+    Digest::result(&mut seeded, &mut out);
+    assert_eq!(out, Xxh64Digest(::oneshot(b"xyz", 7)).to_be_bytes());
+}
+
+#[test]
+fn test_digest_xxh32() {
+    let mut state = xxh32::XXHasher::new_with_seed(0);
+    Digest::input_str(&mut state, "");
+    assert_eq!(Digest::result_str(&mut state).as_slice(), "02cc5d05");
+
+    Digest::reset(&mut state);
+    Digest::input_str(&mut state, "abc");
+    let mut out = [0u8; 4];
+    Digest::result(&mut state, &mut out);
+    assert_eq!(out, Xxh32Digest(xxh32::oneshot(b"abc", 0)).to_be_bytes());
+}
@@ -0,0 +1,367 @@
+//! Finding duplicate files, reading as little as possible.
+//!
+//! Files are grouped by size, then by the xxh64 of their first few KiB,
+//! then by the xxh64 of their whole contents. Files still grouped after
+//! that are compared byte for byte, so a hash collision can't make two
+//! different files look the same. The hashing runs on several threads.
+//!
+//! Hard links to one file are one file, listed with all its paths: there
+//! is nothing to reclaim by deleting one of them. Whatever the options, a
+//! path reached twice through overlapping roots is only counted once. The crate has no
+//! XXH128, so xxh64 it is.
+
+use core::prelude::*;
+use core::default::Default;
+use core::hash::Hasher;
+use core::iter::repeat;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::btree_map;
+use std::collections::hash_map;
+use std::io::{IoResult, IoError, FileType};
+use std::io::fs::{File, readdir, lstat, stat};
+use std::io::util::LimitReader;
+use std::path::Path;
+use std::vec::Vec;
+
+use io::{feed, hash_file, read_full};
+use pool;
+use XXHasher;
+
+#[cfg(test)] use core::iter::range;
+#[cfg(test)] use std::io::{TempDir, FilePermission};
+#[cfg(test)] use std::io::fs::{link, mkdir};
+#[cfg(test)] use walk::write_file;
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct DupeOptions {
+    /// How much of each file the first hash covers.
+    pub prefix_len: u64,
+    /// Smaller files are left alone; by default only empty ones.
+    pub min_size: u64,
+    /// Treat hard links to one file as that one file.
+    pub hard_links: bool,
+    /// Threads hashing files; 0 for one per core.
+    pub threads: usize,
+}
+
+impl Default for DupeOptions {
+    fn default() -> DupeOptions {
+        DupeOptions { prefix_len: 4096, min_size: 1, hard_links: true, threads: 0 }
+    }
+}
+
+/// One file, under every path it was found at.
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct DupeFile {
+    pub paths: Vec<Path>,
+}
+
+/// Files with the same contents.
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct DupeGroup {
+    pub size: u64,
+    pub hash: u64,
+    pub files: Vec<DupeFile>,
+}
+
+impl DupeGroup {
+    /// The bytes freed by keeping just one of the files.
+    pub fn wasted(&self) -> u64 { #![inline]
+        self.size * (self.files.len() as u64 - 1)
+    }
+}
+
+#[derive(Clone, Show)]
+pub struct DupeReport {
+    /// By size, then hash.
+    pub groups: Vec<DupeGroup>,
+    /// Files and directories that couldn't be read, which are left out.
+    pub errors: Vec<(Path, IoError)>,
+    /// Files looked at, counting hard links once.
+    pub scanned: u64,
+}
+
+/// Find the duplicate files under `roots`, which may be files or
+/// directories. Symlinks are not followed.
+pub fn find_duplicates(roots: &[Path], options: &DupeOptions) -> DupeReport {
+    let mut report = DupeReport { groups: Vec::new(), errors: Vec::new(), scanned: 0 };
+
+    let mut seen = Seen { dirs: HashSet::new(), links: HashSet::new(), inodes: HashMap::new() };
+    let mut files = Vec::new();
+    for root in roots.iter() {
+        // following symlinks, as the path itself does
+        let parent = stat(&root.dir_path()).ok().map(|s| (s.unstable.device, s.unstable.inode));
+        walk(root, parent, options, &mut seen, &mut files, &mut report);
+    }
+    report.scanned = files.len() as u64;
+
+    let by_size = buckets(files.into_iter());
+    let candidates: Vec<(u64, DupeFile)> = by_size.into_iter()
+        .flat_map(|(size, files)| repeat(size).zip(files.into_iter()))
+        .collect();
+
+    // small files are done after the first hash
+    let prefix_len = options.prefix_len;
+    let hashes = pool::map(candidates.as_slice(), options.threads, |&(size, ref file)| {
+        if size <= prefix_len {
+            hash_file(&file.paths[0], 0)
+        } else {
+            let mut state = XXHasher::new_with_seed(0);
+            let file = try!(File::open(&file.paths[0]));
+            try!(feed(&mut LimitReader::new(file, prefix_len as usize), &mut state));
+            Ok(state.finish())
+        }
+    });
+    let by_prefix = buckets(keep_ok(candidates, hashes, &mut report.errors)
+        .into_iter().map(|(size, hash, file)| ((size, hash), file)));
+
+    let (done, rest): (Vec<_>, Vec<_>) = by_prefix.into_iter()
+        .partition(|&((size, _), _)| size <= prefix_len);
+    let candidates: Vec<(u64, DupeFile)> = rest.into_iter()
+        .flat_map(|((size, _), files)| repeat(size).zip(files.into_iter()))
+        .collect();
+    let hashes = pool::map(candidates.as_slice(), options.threads, |&(_, ref file)| {
+        hash_file(&file.paths[0], 0)
+    });
+    let by_hash = buckets(keep_ok(candidates, hashes, &mut report.errors)
+        .into_iter().map(|(size, hash, file)| ((size, hash), file)));
+
+    // the keys of the two sets differ by size
+    let mut groups: BTreeMap<(u64, u64), Vec<DupeFile>> = done.into_iter().collect();
+    groups.extend(by_hash.into_iter());
+
+    for ((size, hash), files) in groups.into_iter() {
+        for class in confirm(files, &mut report.errors).into_iter() {
+            report.groups.push(DupeGroup { size: size, hash: hash, files: class });
+        }
+    }
+    report
+}
+
+// What the walk has been through. Directories go by device and inode and
+// links by their directory and name, so overlapping roots, or one root
+// twice, never list a file twice, whatever `hard_links` says.
+struct Seen {
+    dirs: HashSet<(u64, u64)>,
+    links: HashSet<(u64, u64, Vec<u8>)>,
+    // with `hard_links`, the file each inode went to
+    inodes: HashMap<(u64, u64), usize>,
+}
+
+// `parent` is the device and inode of the directory `path` is in, if known.
+fn walk(path: &Path, parent: Option<(u64, u64)>, options: &DupeOptions, seen: &mut Seen,
+        files: &mut Vec<(u64, DupeFile)>, report: &mut DupeReport) {
+    let stat = match lstat(path) {
+        Ok(stat) => stat,
+        Err(e) => return report.errors.push((path.clone(), e)),
+    };
+    let id = (stat.unstable.device, stat.unstable.inode);
+    match stat.kind {
+        FileType::Directory => {
+            if !seen.dirs.insert(id) {
+                return;
+            }
+            match readdir(path) {
+                Ok(children) => for child in children.iter() {
+                    walk(child, Some(id), options, seen, files, report);
+                },
+                Err(e) => report.errors.push((path.clone(), e)),
+            }
+        }
+        FileType::RegularFile if stat.size >= options.min_size => {
+            if let (Some((device, inode)), Some(name)) = (parent, path.filename()) {
+                let mut link = Vec::with_capacity(name.len());
+                link.push_all(name);
+                if !seen.links.insert((device, inode, link)) {
+                    return;
+                }
+            }
+            if options.hard_links {
+                match seen.inodes.entry(id) {
+                    hash_map::Entry::Occupied(e) => {
+                        files[*e.get()].1.paths.push(path.clone());
+                        return;
+                    }
+                    hash_map::Entry::Vacant(e) => { e.insert(files.len()); }
+                }
+            }
+            files.push((stat.size, DupeFile { paths: vec![path.clone()] }));
+        }
+        _ => {}
+    }
+}
+
+// The values under each key that has more than one, in key order.
+fn buckets<K: Ord, V, I: Iterator<Item=(K, V)>>(items: I) -> Vec<(K, Vec<V>)> {
+    let mut map = BTreeMap::new();
+    for (key, value) in items {
+        match map.entry(key) {
+            btree_map::Entry::Occupied(mut e) => e.get_mut().push(value),
+            btree_map::Entry::Vacant(e) => { e.insert(vec![value]); }
+        }
+    }
+    map.into_iter().filter(|&(_, ref values)| values.len() > 1).collect()
+}
+
+// Pair the files with their hashes, moving the failures to `errors`.
+fn keep_ok(files: Vec<(u64, DupeFile)>, hashes: Vec<IoResult<u64>>,
+           errors: &mut Vec<(Path, IoError)>) -> Vec<(u64, u64, DupeFile)> {
+    let mut ok = Vec::with_capacity(files.len());
+    for ((size, file), hash) in files.into_iter().zip(hashes.into_iter()) {
+        match hash {
+            Ok(hash) => ok.push((size, hash, file)),
+            Err(e) => errors.push((file.paths[0].clone(), e)),
+        }
+    }
+    ok
+}
+
+// Split files with equal hashes into classes with equal bytes, keeping
+// the classes of two or more. The first file of a class stands for it; one
+// that can't be read any more leaves, and the next one takes over.
+fn confirm(files: Vec<DupeFile>, errors: &mut Vec<(Path, IoError)>) -> Vec<Vec<DupeFile>> {
+    let mut classes: Vec<Vec<DupeFile>> = Vec::new();
+    'files: for file in files.into_iter() {
+        let mut i = 0;
+        while i < classes.len() {
+            match same_contents(&classes[i][0].paths[0], &file.paths[0]) {
+                Ok(true) => {
+                    classes[i].push(file);
+                    continue 'files;
+                }
+                Ok(false) => i += 1,
+                Err(Unreadable::First(e)) => {
+                    let gone = classes[i].remove(0);
+                    errors.push((gone.paths[0].clone(), e));
+                    if classes[i].is_empty() {
+                        classes.remove(i);
+                    }
+                }
+                Err(Unreadable::Second(e)) => {
+                    errors.push((file.paths[0].clone(), e));
+                    continue 'files;
+                }
+            }
+        }
+        classes.push(vec![file]);
+    }
+    classes.into_iter().filter(|class| class.len() > 1).collect()
+}
+
+// Which of the two files `same_contents` failed on.
+enum Unreadable {
+    First(IoError),
+    Second(IoError),
+}
+
+fn same_contents(a: &Path, b: &Path) -> Result<bool, Unreadable> {
+    let mut a = try!(File::open(a).map_err(Unreadable::First));
+    let mut b = try!(File::open(b).map_err(Unreadable::Second));
+    let mut buf_a: Vec<u8> = repeat(0).take(64 * 1024).collect();
+    let mut buf_b: Vec<u8> = repeat(0).take(64 * 1024).collect();
+    loop {
+        let n = try!(read_full(&mut a, buf_a.as_mut_slice()).map_err(Unreadable::First));
+        let m = try!(read_full(&mut b, buf_b.as_mut_slice()).map_err(Unreadable::Second));
+        if n != m || buf_a.slice_to(n) != buf_b.slice_to(m) {
+            return Ok(false);
+        }
+        if n < buf_a.len() {
+            return Ok(true);
+        }
+    }
+}
+
+#[test]
+fn test_find_duplicates() {
+    let dir = TempDir::new("xxhash-dupes").unwrap();
+    let root = dir.path();
+    write_file(&root.join("a"), b"hello");
+    write_file(&root.join("b"), b"hello");
+    write_file(&root.join("c"), b"hellp");
+    write_file(&root.join("empty1"), b"");
+    write_file(&root.join("empty2"), b"");
+    link(&root.join("a"), &root.join("a-link")).unwrap();
+
+    // the same first KiB, different ends
+    let big: Vec<u8> = range(0, 10000).map(|i| i as u8).collect();
+    write_file(&root.join("d"), big.as_slice());
+    write_file(&root.join("e"), big.as_slice());
+    let mut other = big.clone();
+    other[9999] ^= 1;
+    write_file(&root.join("f"), other.as_slice());
+
+    let options = DupeOptions { prefix_len: 1024, ..Default::default() };
+    let report = find_duplicates(&[root.clone()], &options);
+    assert!(report.errors.is_empty());
+    assert_eq!(report.scanned, 6);
+    assert_eq!(report.groups.len(), 2);
+
+    let small = &report.groups[0];
+    assert_eq!((small.size, small.hash), (5, ::oneshot(b"hello", 0)));
+    assert_eq!(small.files.len(), 2);
+    let linked = small.files.iter().find(|f| f.paths.len() == 2).unwrap();
+    assert!(linked.paths.contains(&root.join("a")) && linked.paths.contains(&root.join("a-link")));
+    assert_eq!(small.wasted(), 5);
+
+    let large = &report.groups[1];
+    assert_eq!(large.size, 10000);
+    assert_eq!(large.files.len(), 2);
+
+    // without link awareness, and counting empty files
+    let options = DupeOptions { hard_links: false, min_size: 0, threads: 1, ..Default::default() };
+    let report = find_duplicates(&[root.clone()], &options);
+    assert_eq!(report.groups.len(), 3);
+    assert_eq!(report.groups[0].size, 0);
+    assert_eq!(report.groups[1].files.len(), 3);
+
+    let report = find_duplicates(&[root.join("missing")], &options);
+    assert_eq!(report.errors.len(), 1);
+}
+
+#[test]
+fn test_overlapping_roots() {
+    let dir = TempDir::new("xxhash-dupes").unwrap();
+    let root = dir.path();
+    mkdir(&root.join("sub"), FilePermission::from_bits_truncate(0o755)).unwrap();
+    write_file(&root.join("sub/x"), b"same");
+    write_file(&root.join("y"), b"same");
+    write_file(&root.join("z"), b"only once");
+
+    // nothing is a duplicate of itself, however often it's reached
+    let roots = [root.clone(), root.join("sub"), root.clone(), root.join("z"), root.join("sub/x")];
+    for &hard_links in [true, false].iter() {
+        let options = DupeOptions { hard_links: hard_links, ..Default::default() };
+        let report = find_duplicates(&roots, &options);
+        assert_eq!(report.scanned, 3);
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].files.len(), 2);
+    }
+
+    // hard links still count as separate files when asked to
+    link(&root.join("y"), &root.join("y-link")).unwrap();
+    let options = DupeOptions { hard_links: false, ..Default::default() };
+    let report = find_duplicates(&roots, &options);
+    assert_eq!(report.groups[0].files.len(), 3);
+}
+
+#[test]
+fn test_unreadable_representative() {
+    let dir = TempDir::new("xxhash-dupes").unwrap();
+    let root = dir.path();
+    write_file(&root.join("a"), b"same");
+    write_file(&root.join("b"), b"same");
+    let file = |name: &str| DupeFile { paths: vec![root.join(name)] };
+
+    let mut errors = Vec::new();
+    let classes = confirm(vec![file("gone"), file("a"), file("b")], &mut errors);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, root.join("gone"));
+    assert_eq!(classes, vec![vec![file("a"), file("b")]]);
+
+    let mut errors = Vec::new();
+    let classes = confirm(vec![file("a"), file("gone"), file("b")], &mut errors);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, root.join("gone"));
+    assert_eq!(classes, vec![vec![file("a"), file("b")]]);
+}
@@ -0,0 +1,306 @@
+//! Feeding `std::io` readers into the hashers, and the hashers into
+//! anything that wants an `std::io::Writer`.
+
+use core::prelude::*;
+use core::fmt;
+use core::hash::{Hasher, Writer};
+
+use std::error::FromError;
+use std::io::{Reader, IoResult, IoError, EndOfFile};
+use std::io::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::vec::Vec;
+
+use {Algorithm, XXHasher};
+use pool;
+use xxh32;
+
+#[cfg(test)] use core::iter::range;
+#[cfg(test)] use std::io::{BufReader, TempDir};
+#[cfg(test)] use walk::write_file;
+
+// big enough to keep the hasher busy, small enough for the stack
+const BUFSIZE: usize = 64 * 1024;
+
+/// Write everything `reader` produces into `state`, until EOF.
+/// Returns the number of bytes consumed.
+pub fn feed<R: Reader, W: Writer>(reader: &mut R, state: &mut W) -> IoResult<u64> {
+    let mut buf = [0u8; BUFSIZE];
+    let mut total = 0u64;
+    loop {
+        match reader.read(&mut buf) {
+            Ok(n) => {
+                state.write(buf.slice_to(n));
+                total += n as u64;
+            }
+            Err(ref e) if e.kind == EndOfFile => return Ok(total),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Read until `buf` is full or `reader` runs dry, unlike `Reader::read`,
+/// which may stop short. Returns the number of bytes read.
+pub fn read_full<R: Reader>(reader: &mut R, buf: &mut [u8]) -> IoResult<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(buf.slice_from_mut(n)) {
+            Ok(m) => n += m,
+            Err(ref e) if e.kind == EndOfFile => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+pub fn hash_reader<R: Reader>(reader: &mut R, seed: u64) -> IoResult<u64> {
+    let mut state = XXHasher::new_with_seed(seed);
+    try!(feed(reader, &mut state));
+    Ok(state.finish())
+}
+
+pub fn hash_reader32<R: Reader>(reader: &mut R, seed: u32) -> IoResult<u32> {
+    let mut state = xxh32::XXHasher::new_with_seed(seed);
+    try!(feed(reader, &mut state));
+    Ok(state.finish())
+}
+
+pub fn hash_file(path: &Path, seed: u64) -> IoResult<u64> {
+    hash_reader(&mut try!(File::open(path)), seed)
+}
+
+pub fn hash_file32(path: &Path, seed: u32) -> IoResult<u32> {
+    hash_reader32(&mut try!(File::open(path)), seed)
+}
+
+/// `hash_file` or `hash_file32`, as `algorithm` says, with the low bits of
+/// `seed`.
+pub fn hash_file_with(path: &Path, algorithm: Algorithm, seed: u64) -> IoResult<u64> {
+    match algorithm {
+        Algorithm::Xxh32 => hash_file32(path, seed as u32).map(|h| h as u64),
+        Algorithm::Xxh64 => hash_file(path, seed),
+    }
+}
+
+/// Hash many files at once on `threads` threads, 0 for one per core.
+/// Each thread streams one file at a time, so no more than `threads`
+/// files are open and buffered at any moment. The results are in the
+/// order of `paths`.
+pub fn hash_files(paths: &[Path], algorithm: Algorithm, seed: u64, threads: usize)
+                  -> Vec<IoResult<u64>> {
+    pool::map(paths, threads, |path| hash_file_with(path, algorithm, seed))
+}
+
+/// Stops a `*_with_progress` hash from another thread. Clones share the
+/// flag.
+#[derive(Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// The hash stops before its next read.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub enum Error {
+    Io(IoError),
+    /// The `CancelToken` was cancelled. No digest comes out of a partial
+    /// read.
+    Cancelled,
+}
+
+impl FromError<IoError> for Error {
+    fn from_error(err: IoError) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl fmt::String for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// `feed`, calling `progress` with the bytes consumed so far whenever
+/// another `interval` of them has gone by, and once more at the end for
+/// the rest. `cancel` is checked before every read.
+pub fn feed_with_progress<R, W, F>(reader: &mut R, state: &mut W, interval: u64,
+                                   cancel: &CancelToken, mut progress: F) -> Result<u64, Error>
+    where R: Reader, W: Writer, F: FnMut(u64)
+{
+    let mut buf = [0u8; BUFSIZE];
+    let mut total = 0u64;
+    let mut reported = 0u64;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        match reader.read(&mut buf) {
+            Ok(n) => {
+                state.write(buf.slice_to(n));
+                total += n as u64;
+                if total > reported && total - reported >= interval {
+                    progress(total);
+                    reported = total;
+                }
+            }
+            Err(ref e) if e.kind == EndOfFile => break,
+            Err(e) => return Err(Error::Io(e)),
+        }
+    }
+    if total > reported {
+        progress(total);
+    }
+    Ok(total)
+}
+
+pub fn hash_reader_with_progress<R, F>(reader: &mut R, seed: u64, interval: u64,
+                                       cancel: &CancelToken, progress: F) -> Result<u64, Error>
+    where R: Reader, F: FnMut(u64)
+{
+    let mut state = XXHasher::new_with_seed(seed);
+    try!(feed_with_progress(reader, &mut state, interval, cancel, progress));
+    Ok(state.finish())
+}
+
+pub fn hash_reader32_with_progress<R, F>(reader: &mut R, seed: u32, interval: u64,
+                                         cancel: &CancelToken, progress: F) -> Result<u32, Error>
+    where R: Reader, F: FnMut(u64)
+{
+    let mut state = xxh32::XXHasher::new_with_seed(seed);
+    try!(feed_with_progress(reader, &mut state, interval, cancel, progress));
+    Ok(state.finish())
+}
+
+pub fn hash_file_with_progress<F>(path: &Path, seed: u64, interval: u64,
+                                  cancel: &CancelToken, progress: F) -> Result<u64, Error>
+    where F: FnMut(u64)
+{
+    hash_reader_with_progress(&mut try!(File::open(path)), seed, interval, cancel, progress)
+}
+
+// So `io::util::copy` and friends can write straight into a hasher.
+
+impl ::std::io::Writer for XXHasher {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> { #![inline]
+        Writer::write(self, buf);
+        Ok(())
+    }
+}
+
+impl ::std::io::Writer for xxh32::XXHasher {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> { #![inline]
+        Writer::write(self, buf);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_hash_reader() {
+    // more than one buffer's worth, not a multiple of the block size
+    let data: Vec<u8> = range(0, 3 * BUFSIZE + 7).map(|i| i as u8).collect();
+
+    for &len in [0, 1, 31, BUFSIZE, data.len()].iter() {
+        let input = data.slice_to(len);
+        assert_eq!(hash_reader(&mut BufReader::new(input), 42).unwrap(),
+                   ::oneshot(input, 42));
+        assert_eq!(hash_reader32(&mut BufReader::new(input), 42).unwrap(),
+                   xxh32::oneshot(input, 42));
+    }
+}
+
+#[test]
+fn test_io_writer() {
+    let data = b"Nobody inspects the spammish repetition";
+
+    let mut state = XXHasher::new_with_seed(0);
+    ::std::io::Writer::write(&mut state, data).unwrap();
+    assert_eq!(state.finish(), ::oneshot(data, 0));
+}
+
+#[test]
+fn test_hash_files() {
+    let dir = TempDir::new("xxhash-io").unwrap();
+    let mut paths = Vec::new();
+    for i in range(0, 20) {
+        let path = dir.path().join(format!("{}", i));
+        let data: Vec<u8> = range(0, i * 1000).map(|j| j as u8).collect();
+        write_file(&path, data.as_slice());
+        paths.push(path);
+    }
+    paths.insert(3, dir.path().join("missing"));
+
+    for &threads in [0, 1, 4].iter() {
+        let hashes = hash_files(paths.as_slice(), Algorithm::Xxh64, 7, threads);
+        assert_eq!(hashes.len(), 21);
+        assert!(hashes[3].is_err());
+        for (path, hash) in paths.iter().zip(hashes.iter()) {
+            if let Ok(hash) = *hash {
+                assert_eq!(hash, hash_file(path, 7).unwrap());
+            }
+        }
+    }
+
+    let second: Vec<u8> = range(0, 1000).map(|j| j as u8).collect();
+    assert_eq!(hash_files(paths.slice_to(2), Algorithm::Xxh32, 7, 2),
+               vec![Ok(xxh32::oneshot(&[], 7) as u64), Ok(xxh32::oneshot(second.as_slice(), 7) as u64)]);
+}
+
+#[test]
+fn test_progress() {
+    let data: Vec<u8> = range(0, 5 * BUFSIZE / 2).map(|i| i as u8).collect();
+    let cancel = CancelToken::new();
+
+    let mut calls = Vec::new();
+    let hash = hash_reader_with_progress(&mut BufReader::new(data.as_slice()), 1, 2 * BUFSIZE as u64,
+                                         &cancel, |n| calls.push(n)).unwrap();
+    assert_eq!(hash, ::oneshot(data.as_slice(), 1));
+    assert_eq!(calls, vec![2 * BUFSIZE as u64, data.len() as u64]);
+
+    let mut calls = Vec::new();
+    let hash = hash_reader32_with_progress(&mut BufReader::new(data.as_slice()), 1, 0,
+                                           &cancel, |n| calls.push(n)).unwrap();
+    assert_eq!(hash, xxh32::oneshot(data.as_slice(), 1));
+    assert_eq!(calls.len(), 3);
+
+    let mut calls = 0;
+    assert_eq!(hash_reader_with_progress(&mut BufReader::new(&[]), 0, 0, &cancel, |_| calls += 1),
+               Ok(::oneshot(&[], 0)));
+    assert_eq!(calls, 0);
+}
+
+#[test]
+fn test_cancel() {
+    let data: Vec<u8> = range(0, 4 * BUFSIZE).map(|i| i as u8).collect();
+    let cancel = CancelToken::new();
+    let other = cancel.clone();
+
+    // cancelled partway, from the callback
+    let mut last = 0;
+    let result = hash_reader_with_progress(&mut BufReader::new(data.as_slice()), 0, 0, &cancel, |n| {
+        last = n;
+        other.cancel();
+    });
+    assert_eq!(result, Err(Error::Cancelled));
+    assert_eq!(last, BUFSIZE as u64);
+
+    // and before the start
+    assert!(cancel.is_cancelled());
+    assert_eq!(hash_reader_with_progress(&mut BufReader::new(&[]), 0, 0, &cancel, |_| {}),
+               Err(Error::Cancelled));
+}
@@ -18,29 +18,74 @@
 // It's probably best to consider this code as an excercise in writing
 // good tests.
 
+// The hashers only need `core`. Everything that touches the outside
+// world (I/O, random seeds) is behind the default `std` feature, so the
+// crate can be used on targets without an OS or an allocator.
+
 #![crate_name="xxhash"]
 #![crate_type="lib"]
 
+#![no_std]
 #![feature(int_uint)]
 #![allow(unused_assignments, unused_variables)] // `read_ptr!`
 #![allow(unstable)]
 
 #[macro_use] extern crate core;
+#[cfg(any(feature = "std", test))]
+#[macro_use] extern crate std;
+
+#[cfg(feature = "serialize")]
+extern crate "rustc-serialize" as rustc_serialize;
+#[cfg(feature = "digest")]
+extern crate "rust-crypto" as crypto;
 
 #[cfg(test)]
 extern crate test;
 
-use std::mem::{uninitialized, transmute};
-use std::num::Int;
-use std::raw::{Repr};
-use std::ptr::{copy_memory};
-use std::hash::{Hash, Hasher, Writer};
-use std::default::Default;
+// `#[derive]` expands to `::std::` paths, so give it something to find
+// when the real `std` isn't linked.
+#[cfg(not(any(feature = "std", test)))]
+mod std {
+    pub use core::{clone, cmp, default, fmt, hash, marker, option};
+}
 
+use core::prelude::*;
+use core::mem::{uninitialized, transmute};
+use core::num::Int;
+use core::raw::{Repr};
+use core::ptr::{copy_memory};
+use core::hash::{Hash, Hasher, Writer};
+use core::default::Default;
+
+#[cfg(test)] use core::iter::range;
+#[cfg(test)] use std::vec::Vec;
 #[cfg(test)] use test::Bencher;
 
 pub mod macros;
 pub mod xxh32;
+pub mod canonical;
+pub mod multihash;
+pub mod lz4;
+pub mod zstd;
+pub mod spark;
+pub mod sectors;
+pub mod page;
+pub mod cdc;
+#[cfg(feature = "std")] pub mod io;
+#[cfg(feature = "std")] pub mod parquet;
+#[cfg(feature = "std")] pub mod rocksdb;
+#[cfg(feature = "std")] pub mod log;
+#[cfg(feature = "std")] pub mod merkle;
+#[cfg(feature = "std")] pub mod blockmap;
+#[cfg(feature = "std")] pub mod delta;
+#[cfg(feature = "std")] pub mod tree;
+#[cfg(feature = "std")] pub mod dupes;
+#[cfg(feature = "std")] pub mod manifest;
+#[cfg(feature = "std")] pub mod codegen;
+#[cfg(feature = "digest")] mod digest_impls;
+mod bytes;
+#[cfg(feature = "std")] mod pool;
+#[cfg(feature = "std")] mod walk;
 
 // large prime, new_with_seed(0) is so boring
 const HAPPY_SEED: u64 = 18446744073709551557_u64;
@@ -86,6 +131,12 @@ impl XXHasher {
     pub fn new() -> XXHasher { #![inline]
         XXHasher::new_with_seed(HAPPY_SEED)
     }
+
+    /// Seeded from the thread-local RNG.
+    #[cfg(feature = "std")]
+    pub fn new_random() -> XXHasher { #![inline]
+        XXHasher::new_with_seed(::std::rand::random())
+    }
 }
 
 impl Writer for XXHasher {
@@ -271,6 +322,127 @@ pub fn hash_with_seed<T: Hash<XXHasher>>(seed: u64, value: &T) -> u64 { #![inlin
     state.finish()
 }
 
+/// Order-independent hash of a collection, e.g. the contents of a `HashSet`.
+pub fn hash_unordered<T, I>(iter: I) -> u64
+    where T: Hash<XXHasher>, I: Iterator<Item=T>
+{
+    let mut state = MultisetHasher::new();
+    for value in iter {
+        state.insert(&value);
+    }
+    state.finish()
+}
+
+/// Like `hash_unordered`, but takes each element together with the number
+/// of times it occurs, e.g. the entries of a counting `HashMap`.
+pub fn hash_multiset<T, I>(iter: I) -> u64
+    where T: Hash<XXHasher>, I: Iterator<Item=(T, usize)>
+{
+    let mut state = MultisetHasher::new();
+    for (value, count) in iter {
+        state.insert_n(&value, count as u64);
+    }
+    state.finish()
+}
+
+/// Incremental state behind `hash_unordered` and `hash_multiset`.
+///
+/// Every element is hashed on its own and the digests are summed, so the
+/// result does not depend on the order of insertion and elements can be
+/// taken out again with `remove`.
+#[derive(Copy)]
+pub struct MultisetHasher {
+    sum: u64,
+    count: u64,
+    seed: u64,
+}
+
+impl MultisetHasher {
+    pub fn new_with_seed(seed: u64) -> MultisetHasher { #![inline]
+        MultisetHasher { sum: 0, count: 0, seed: seed }
+    }
+
+    pub fn new() -> MultisetHasher { #![inline]
+        MultisetHasher::new_with_seed(HAPPY_SEED)
+    }
+
+    pub fn insert<T: ?Sized + Hash<XXHasher>>(&mut self, value: &T) { #![inline]
+        self.insert_n(value, 1)
+    }
+
+    pub fn insert_n<T: ?Sized + Hash<XXHasher>>(&mut self, value: &T, n: u64) {
+        self.sum += self.element(value) * n;
+        self.count += n;
+    }
+
+    /// Undo an earlier `insert`. Removing something that was never
+    /// inserted leaves the state meaningless.
+    pub fn remove<T: ?Sized + Hash<XXHasher>>(&mut self, value: &T) { #![inline]
+        self.remove_n(value, 1)
+    }
+
+    pub fn remove_n<T: ?Sized + Hash<XXHasher>>(&mut self, value: &T, n: u64) {
+        self.sum -= self.element(value) * n;
+        self.count -= n;
+    }
+
+    /// Number of elements currently in the set, counting duplicates.
+    pub fn len(&self) -> u64 { #![inline]
+        self.count
+    }
+
+    /// The sum alone is linear, so it goes through the full hash
+    /// once more together with the count.
+    pub fn finish(&self) -> u64 {
+        let mut state = XXHasher::new_with_seed(self.seed);
+        (self.sum, self.count).hash(&mut state);
+        state.finish()
+    }
+
+    fn element<T: ?Sized + Hash<XXHasher>>(&self, value: &T) -> u64 { #![inline]
+        let mut state = XXHasher::new_with_seed(self.seed);
+        value.hash(&mut state);
+        state.finish()
+    }
+}
+
+impl Clone for MultisetHasher {
+    fn clone(&self) -> MultisetHasher { #![inline]
+        *self
+    }
+}
+
+impl Default for MultisetHasher {
+    fn default() -> MultisetHasher { #![inline]
+        MultisetHasher::new()
+    }
+}
+
+/// The two hashes, for formats and tools that let you pick.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum Algorithm {
+    Xxh32,
+    Xxh64,
+}
+
+impl Algorithm {
+    /// Bytes in a digest.
+    pub fn digest_len(&self) -> usize { #![inline]
+        match *self {
+            Algorithm::Xxh32 => 4,
+            Algorithm::Xxh64 => 8,
+        }
+    }
+
+    /// Hash `input` with the low bits of `seed`, as wide as the digest.
+    pub fn oneshot(&self, input: &[u8], seed: u64) -> u64 { #![inline]
+        match *self {
+            Algorithm::Xxh32 => xxh32::oneshot(input, seed as u32) as u64,
+            Algorithm::Xxh64 => oneshot(input, seed),
+        }
+    }
+}
+
 /// the official sanity test
 #[cfg(test)]
 fn test_base<F>(f: F) where F: Fn(&[u8], u64) -> u64 {
@@ -420,6 +592,46 @@ fn test_hash_no_concat_alias() {
     assert!(hash(&v) != hash(&w));
 }
 
+#[test]
+fn test_literal_macro() {
+    // what `codegen` writes for it
+    static PING: u64 = 0xA54F4F0E2F7CCDA1;
+
+    assert_eq!(xxh64!("Message::Ping"), PING);
+    assert_eq!(xxh64!("Message::Ping"), oneshot(b"Message::Ping", 0));
+    assert_eq!(xxh64!("Message::Ping", 1), 0x753F72A0DB748FE6);
+}
+
+#[test]
+fn test_hash_unordered() {
+    let a = ["foo", "bar", "baz"];
+    let b = ["baz", "foo", "bar"];
+    assert_eq!(hash_unordered(a.iter()), hash_unordered(b.iter()));
+    assert!(hash_unordered(a.iter()) != hash_unordered(a.slice_to(2).iter()));
+
+    // duplicates count
+    let c = ["foo", "foo", "bar", "baz"];
+    assert!(hash_unordered(a.iter()) != hash_unordered(c.iter()));
+    let d = [("bar", 1), ("foo", 2), ("baz", 1)];
+    assert_eq!(hash_unordered(c.iter()), hash_multiset(d.iter().map(|&(v, n)| (v, n))));
+}
+
+#[test]
+fn test_multiset_incremental() {
+    let mut state = MultisetHasher::new();
+    state.insert("foo");
+    state.insert("bar");
+    let before = state.finish();
+
+    state.insert("baz");
+    assert!(state.finish() != before);
+    assert_eq!(state.len(), 3);
+
+    state.remove("baz");
+    assert_eq!(state.finish(), before);
+    assert_eq!(state.finish(), hash_unordered(["bar", "foo"].iter()));
+}
+
 #[bench]
 fn bench_str_under_8_bytes(b: &mut Bencher) {
     let s = "foo";
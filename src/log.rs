@@ -0,0 +1,492 @@
+//! An append-only log of checksummed records, for write-ahead logs and
+//! the like.
+//!
+//! A log starts with an 8-byte header: the magic `XXLG`, the format
+//! version, the checksum algorithm (0 for xxh32, 1 for xxh64) and two zero
+//! bytes. Each record follows as
+//!
+//!     length: u32 LE | checksum: u32 or u64 LE | payload
+//!
+//! with the checksum (seed 0) covering the length bytes and the payload.
+//!
+//! A crash in the middle of an append leaves a torn record at the end of
+//! the log, which `LogReader` reports apart from a checksum mismatch.
+//! `recover` cuts the log back to its last intact record so appending can
+//! resume.
+
+use core::prelude::*;
+use core::fmt;
+use core::hash::{Hasher, Writer};
+
+use std::error::FromError;
+use std::io::{Reader, IoResult, IoError, InvalidInput, Open, Append, ReadWrite, Write};
+use std::io::fs::File;
+use std::io::util::LimitReader;
+use std::path::Path;
+use std::vec::Vec;
+
+use bytes::{le32, le64, put_le32, put_le64};
+use io::read_full;
+use {Algorithm, XXHasher};
+use xxh32;
+
+#[cfg(test)] use core::iter::range;
+#[cfg(test)] use std::io::{BufReader, TempDir};
+
+const MAGIC: &'static [u8] = b"XXLG";
+
+pub const VERSION: u8 = 1;
+
+pub const HEADER_LEN: u64 = 8;
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub enum Error {
+    Io(IoError),
+    /// Not a log, or one from a newer version.
+    BadHeader,
+    /// The log ends partway through the record at this offset, as a crash
+    /// in the middle of an append leaves it.
+    TornWrite(u64),
+    /// The record at this offset fails its checksum.
+    Corrupt(u64),
+}
+
+impl FromError<IoError> for Error {
+    fn from_error(err: IoError) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl fmt::String for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::BadHeader => write!(f, "not a record log"),
+            Error::TornWrite(offset) => write!(f, "torn write at offset {}", offset),
+            Error::Corrupt(offset) => write!(f, "corrupt record at offset {}", offset),
+        }
+    }
+}
+
+/// The header a log checksummed with `algorithm` starts with.
+pub fn header(algorithm: Algorithm) -> [u8; 8] {
+    let code = match algorithm { Algorithm::Xxh32 => 0, Algorithm::Xxh64 => 1 };
+    [MAGIC[0], MAGIC[1], MAGIC[2], MAGIC[3], VERSION, code, 0, 0]
+}
+
+fn parse_header(header: &[u8]) -> Option<Algorithm> {
+    if header.slice_to(4) != MAGIC || header[4] != VERSION || header[6] != 0 || header[7] != 0 {
+        return None;
+    }
+    match header[5] {
+        0 => Some(Algorithm::Xxh32),
+        1 => Some(Algorithm::Xxh64),
+        _ => None,
+    }
+}
+
+fn checksum(algorithm: Algorithm, len: &[u8], payload: &[u8]) -> u64 {
+    match algorithm {
+        Algorithm::Xxh32 => {
+            let mut state = xxh32::XXHasher::new_with_seed(0);
+            state.write(len);
+            state.write(payload);
+            state.finish() as u64
+        }
+        Algorithm::Xxh64 => {
+            let mut state = XXHasher::new_with_seed(0);
+            state.write(len);
+            state.write(payload);
+            state.finish()
+        }
+    }
+}
+
+/// When `LogWriter` makes appended records durable.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum SyncPolicy {
+    /// Only on explicit `sync` calls, and whenever the OS gets to it.
+    Never,
+    /// After every record. Durable, and slow.
+    Always,
+    /// After every this many records.
+    Every(u32),
+}
+
+/// Flushing written data to stable storage.
+pub trait Fsync {
+    fn fsync(&mut self) -> IoResult<()>;
+}
+
+impl Fsync for File {
+    fn fsync(&mut self) -> IoResult<()> { #![inline]
+        // the records are all that matter, not the timestamps
+        self.datasync()
+    }
+}
+
+/// In-memory logs have nothing to sync.
+impl Fsync for Vec<u8> {
+    fn fsync(&mut self) -> IoResult<()> { #![inline]
+        Ok(())
+    }
+}
+
+pub struct LogWriter<W> {
+    inner: W,
+    algorithm: Algorithm,
+    policy: SyncPolicy,
+    unsynced: u32,
+}
+
+impl<W: ::std::io::Writer + Fsync> LogWriter<W> {
+    /// Start a new log in `inner`, writing its header.
+    pub fn new(mut inner: W, algorithm: Algorithm, policy: SyncPolicy) -> IoResult<LogWriter<W>> {
+        try!(inner.write(&header(algorithm)));
+        Ok(LogWriter::resume(inner, algorithm, policy))
+    }
+
+    /// Append to an existing log that `inner` is positioned at the end
+    /// of, as after `recover`.
+    pub fn resume(inner: W, algorithm: Algorithm, policy: SyncPolicy) -> LogWriter<W> {
+        LogWriter { inner: inner, algorithm: algorithm, policy: policy, unsynced: 0 }
+    }
+
+    pub fn append(&mut self, payload: &[u8]) -> IoResult<()> {
+        if payload.len() as u64 > 0xFFFFFFFF {
+            return Err(IoError { kind: InvalidInput, desc: "record too long", detail: None });
+        }
+        let sum_len = self.algorithm.digest_len();
+        let mut head = [0u8; 12];
+        put_le32(&mut head, 0, payload.len() as u32);
+        let sum = checksum(self.algorithm, head.slice_to(4), payload);
+        if sum_len == 4 {
+            put_le32(&mut head, 4, sum as u32);
+        } else {
+            put_le64(&mut head, 4, sum);
+        }
+        try!(self.inner.write(head.slice_to(4 + sum_len)));
+        try!(self.inner.write(payload));
+
+        self.unsynced += 1;
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flush and fsync everything appended so far.
+    pub fn sync(&mut self) -> IoResult<()> {
+        try!(self.inner.flush());
+        try!(self.inner.fsync());
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    pub fn algorithm(&self) -> Algorithm { #![inline]
+        self.algorithm
+    }
+
+    pub fn get_ref(&self) -> &W { #![inline]
+        &self.inner
+    }
+
+    /// Unsynced records stay unsynced.
+    pub fn into_inner(self) -> W { #![inline]
+        self.inner
+    }
+}
+
+impl LogWriter<File> {
+    /// Create a new log at `path`, replacing any file there.
+    pub fn create(path: &Path, algorithm: Algorithm, policy: SyncPolicy)
+                  -> IoResult<LogWriter<File>> {
+        let mut log = try!(LogWriter::new(try!(File::create(path)), algorithm, policy));
+        try!(log.sync());
+        Ok(log)
+    }
+
+    /// Recover the log at `path` and open it for appending.
+    pub fn open(path: &Path, policy: SyncPolicy) -> Result<(LogWriter<File>, Recovery), Error> {
+        let recovery = try!(recover(path));
+        let file = try!(File::open_mode(path, Append, Write));
+        Ok((LogWriter::resume(file, recovery.algorithm, policy), recovery))
+    }
+}
+
+/// Iterates over the payloads of a log, stopping after the first error.
+pub struct LogReader<R> {
+    inner: R,
+    algorithm: Algorithm,
+    offset: u64,
+    records: u64,
+    done: bool,
+}
+
+impl<R: Reader> LogReader<R> {
+    /// Read the header of the log in `inner`.
+    pub fn new(mut inner: R) -> Result<LogReader<R>, Error> {
+        let mut header = [0u8; 8];
+        if try!(read_full(&mut inner, &mut header)) < header.len() {
+            return Err(Error::BadHeader);
+        }
+        let algorithm = match parse_header(&header) {
+            Some(algorithm) => algorithm,
+            None => return Err(Error::BadHeader),
+        };
+        Ok(LogReader { inner: inner, algorithm: algorithm, offset: HEADER_LEN, records: 0, done: false })
+    }
+
+    pub fn algorithm(&self) -> Algorithm { #![inline]
+        self.algorithm
+    }
+
+    /// The length of the log up to the end of the last intact record
+    /// read so far.
+    pub fn valid_len(&self) -> u64 { #![inline]
+        self.offset
+    }
+
+    /// Intact records read so far.
+    pub fn records(&self) -> u64 { #![inline]
+        self.records
+    }
+
+    fn read_record(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let start = self.offset;
+        let head_len = 4 + self.algorithm.digest_len();
+        let mut head = [0u8; 12];
+        match try!(read_full(&mut self.inner, head.slice_to_mut(head_len))) {
+            0 => return Ok(None),
+            n if n < head_len => return Err(Error::TornWrite(start)),
+            _ => {}
+        }
+        let len = le32(&head, 0) as u64;
+        let stored = if head_len == 8 { le32(&head, 4) as u64 } else { le64(&head, 4) };
+
+        // a corrupt length only costs as much memory as the log holds
+        let payload = try!(LimitReader::new(&mut self.inner, len as usize).read_to_end());
+        if (payload.len() as u64) < len {
+            return Err(Error::TornWrite(start));
+        }
+        if checksum(self.algorithm, head.slice_to(4), payload.as_slice()) != stored {
+            return Err(Error::Corrupt(start));
+        }
+        self.offset += head_len as u64 + len;
+        self.records += 1;
+        Ok(Some(payload))
+    }
+}
+
+impl LogReader<File> {
+    pub fn open(path: &Path) -> Result<LogReader<File>, Error> {
+        LogReader::new(try!(File::open(path)))
+    }
+}
+
+impl<R: Reader> Iterator for LogReader<R> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        if self.done {
+            return None;
+        }
+        match self.read_record() {
+            Ok(Some(payload)) => Some(Ok(payload)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// What `recover` found and did.
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct Recovery {
+    pub algorithm: Algorithm,
+    /// Intact records kept.
+    pub records: u64,
+    /// The length of the log now.
+    pub len: u64,
+    /// Bytes cut off after the last intact record.
+    pub truncated: u64,
+    /// The torn write or corruption the scan stopped at, if any.
+    pub error: Option<Error>,
+}
+
+/// Scan the log at `path` and truncate it after the last intact record.
+/// Everything behind the first torn or corrupt record goes, including any
+/// intact records after a corrupt one.
+pub fn recover(path: &Path) -> Result<Recovery, Error> {
+    let mut file = try!(File::open_mode(path, Open, ReadWrite));
+    let file_len = try!(file.stat()).size;
+
+    let (algorithm, records, len, error) = {
+        let mut reader = try!(LogReader::new(&mut file));
+        let mut error = None;
+        for record in reader.by_ref() {
+            match record {
+                Ok(_) => {}
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                Err(e) => error = Some(e),
+            }
+        }
+        (reader.algorithm, reader.records, reader.offset, error)
+    };
+
+    if len < file_len {
+        try!(file.truncate(len as i64));
+        try!(file.fsync());
+    }
+    Ok(Recovery {
+        algorithm: algorithm,
+        records: records,
+        len: len,
+        truncated: file_len - len,
+        error: error,
+    })
+}
+
+#[cfg(test)]
+fn build_log(algorithm: Algorithm, records: &[&[u8]]) -> Vec<u8> {
+    let mut log = LogWriter::new(Vec::new(), algorithm, SyncPolicy::Never).unwrap();
+    for record in records.iter() {
+        log.append(*record).unwrap();
+    }
+    log.into_inner()
+}
+
+#[cfg(test)]
+static RECORDS: [&'static [u8]; 3] = [b"first", b"", b"the third record"];
+
+#[test]
+fn test_roundtrip() {
+    for &algorithm in [Algorithm::Xxh32, Algorithm::Xxh64].iter() {
+        let log = build_log(algorithm, &RECORDS);
+        assert_eq!(log.slice_to(8), header(algorithm).as_slice());
+        assert_eq!(le32(log.as_slice(), 8), 5);
+        assert_eq!(log.len() as u64,
+                   HEADER_LEN + 3 * (4 + algorithm.digest_len() as u64) + 21);
+
+        let mut reader = LogReader::new(BufReader::new(log.as_slice())).unwrap();
+        assert_eq!(reader.algorithm(), algorithm);
+        let read: Vec<Vec<u8>> = reader.by_ref().map(|r| r.unwrap()).collect();
+        assert_eq!(read.len(), 3);
+        for (got, want) in read.iter().zip(RECORDS.iter()) {
+            assert_eq!(got.as_slice(), *want);
+        }
+        assert_eq!(reader.records(), 3);
+        assert_eq!(reader.valid_len(), log.len() as u64);
+    }
+
+    // the checksum covers the length as well
+    let log = build_log(Algorithm::Xxh64, &[b"abc".as_slice()]);
+    assert_eq!(le64(log.as_slice(), 12), ::oneshot(&[3, 0, 0, 0, b'a', b'b', b'c'], 0));
+}
+
+#[test]
+fn test_torn_write() {
+    let log = build_log(Algorithm::Xxh32, &RECORDS);
+    let last = (log.len() - (8 + 16)) as u64;
+    for len in range(last as usize + 1, log.len()) {
+        let mut reader = LogReader::new(BufReader::new(log.slice_to(len))).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.next(), Some(Err(Error::TornWrite(last))));
+        assert_eq!(reader.next(), None);
+        assert_eq!(reader.valid_len(), last);
+    }
+
+    // cut right between records is a clean end
+    let reader = LogReader::new(BufReader::new(log.slice_to(last as usize))).unwrap();
+    assert_eq!(reader.count(), 2);
+}
+
+#[test]
+fn test_corrupt() {
+    let mut log = build_log(Algorithm::Xxh64, &RECORDS);
+    log[HEADER_LEN as usize + 12 + 2] ^= 0x10;
+    let mut reader = LogReader::new(BufReader::new(log.as_slice())).unwrap();
+    assert_eq!(reader.next(), Some(Err(Error::Corrupt(HEADER_LEN))));
+    assert_eq!(reader.next(), None);
+
+    let mut log = build_log(Algorithm::Xxh64, &RECORDS);
+    log[4] = VERSION + 1;
+    assert_eq!(LogReader::new(BufReader::new(log.as_slice())).err(), Some(Error::BadHeader));
+    assert_eq!(LogReader::new(BufReader::new(b"XXLG")).err(), Some(Error::BadHeader));
+}
+
+#[cfg(test)]
+struct CountingSyncs {
+    buf: Vec<u8>,
+    syncs: usize,
+}
+
+#[cfg(test)]
+impl ::std::io::Writer for CountingSyncs {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.buf.push_all(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Fsync for CountingSyncs {
+    fn fsync(&mut self) -> IoResult<()> {
+        self.syncs += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sync_policy() {
+    for &(policy, syncs) in [(SyncPolicy::Never, 0), (SyncPolicy::Always, 5),
+                             (SyncPolicy::Every(2), 2)].iter() {
+        let inner = CountingSyncs { buf: Vec::new(), syncs: 0 };
+        let mut log = LogWriter::new(inner, Algorithm::Xxh32, policy).unwrap();
+        for i in range(0u8, 5) {
+            log.append(&[i]).unwrap();
+        }
+        assert_eq!(log.get_ref().syncs, syncs);
+    }
+}
+
+#[test]
+fn test_recover() {
+    let dir = TempDir::new("xxhash-log").unwrap();
+    let path = dir.path().join("wal");
+
+    let mut log = LogWriter::create(&path, Algorithm::Xxh64, SyncPolicy::Always).unwrap();
+    for record in RECORDS.iter() {
+        log.append(*record).unwrap();
+    }
+    drop(log);
+
+    // a torn append
+    let intact = File::open(&path).unwrap().stat().unwrap().size;
+    let mut file = File::open_mode(&path, Append, Write).unwrap();
+    ::std::io::Writer::write(&mut file, &[10, 0, 0, 0, 1, 2, 3]).unwrap();
+    drop(file);
+
+    let (mut log, recovery) = LogWriter::open(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(recovery, Recovery {
+        algorithm: Algorithm::Xxh64,
+        records: 3,
+        len: intact,
+        truncated: 7,
+        error: Some(Error::TornWrite(intact)),
+    });
+    log.append(b"after recovery").unwrap();
+    drop(log);
+
+    let records: Vec<Vec<u8>> = LogReader::open(&path).unwrap().map(|r| r.unwrap()).collect();
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[3].as_slice(), b"after recovery");
+    assert_eq!(recover(&path).unwrap().error, None);
+}
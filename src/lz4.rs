@@ -0,0 +1,412 @@
+//! Checksum verification for the LZ4 frame format, without decompressing.
+//!
+//! All LZ4 frame checksums are xxh32 with seed 0:
+//!
+//! * the header checksum byte is `(xxh32(descriptor) >> 8) & 0xFF`,
+//! * each block may carry the xxh32 of its stored (compressed) bytes,
+//! * the frame may end with the xxh32 of the decompressed content.
+//!
+//! The first two are checked by `verify_frame`. The content checksum
+//! needs the decompressed bytes, which `ContentVerifier` consumes as a
+//! stream. Every error carries the offset from the start of the frame of
+//! the field that failed.
+//!
+//! https://github.com/lz4/lz4/blob/dev/doc/lz4_Frame_format.md
+
+use core::prelude::*;
+use core::fmt;
+use core::hash::{Hasher, Writer};
+
+use bytes::le32;
+use xxh32;
+
+use self::ErrorKind::*;
+
+pub const MAGIC: u32 = 0x184D2204;
+
+// skippable frames use any magic number from this range
+const SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+const SKIPPABLE_MASK: u32 = 0xFFFFFFF0;
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum ErrorKind {
+    Truncated,
+    BadMagic(u32),
+    UnsupportedVersion(u8),
+    ReservedBits,
+    BadBlockMaxSize(u8),
+    HeaderChecksum { stored: u8, computed: u8 },
+    BlockTooLarge(usize),
+    BlockChecksum { stored: u32, computed: u32 },
+    ContentChecksum { stored: u32, computed: u32 },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// Offset from the start of the frame.
+    pub offset: usize,
+}
+
+impl fmt::String for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(match self.kind {
+            Truncated => write!(f, "frame truncated"),
+            BadMagic(m) => write!(f, "not an LZ4 frame (magic {:#010x})", m),
+            UnsupportedVersion(v) => write!(f, "unsupported frame version {}", v),
+            ReservedBits => write!(f, "reserved bits set"),
+            BadBlockMaxSize(s) => write!(f, "invalid block maximum size {}", s),
+            HeaderChecksum { stored, computed } =>
+                write!(f, "header checksum {:#04x}, expected {:#04x}", stored, computed),
+            BlockTooLarge(n) => write!(f, "block of {} bytes exceeds the maximum", n),
+            BlockChecksum { stored, computed } =>
+                write!(f, "block checksum {:#010x}, expected {:#010x}", stored, computed),
+            ContentChecksum { stored, computed } =>
+                write!(f, "content checksum {:#010x}, expected {:#010x}", stored, computed),
+        });
+        write!(f, " at offset {}", self.offset)
+    }
+}
+
+fn error<T>(kind: ErrorKind, offset: usize) -> Result<T, Error> { #![inline]
+    Err(Error { kind: kind, offset: offset })
+}
+
+fn read32(frame: &[u8], at: usize) -> Result<u32, Error> { #![inline]
+    if frame.len() < at + 4 {
+        return error(Truncated, at);
+    }
+    Ok(le32(frame, at))
+}
+
+/// The parsed frame header.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct FrameDescriptor {
+    pub block_independence: bool,
+    pub block_checksum: bool,
+    pub content_checksum: bool,
+    pub content_size: Option<u64>,
+    pub dict_id: Option<u32>,
+    pub block_max_size: usize,
+    /// Including the magic number and the header checksum.
+    pub header_len: usize,
+}
+
+/// Parse the frame header and check its checksum byte.
+pub fn parse_header(frame: &[u8]) -> Result<FrameDescriptor, Error> {
+    let magic = try!(read32(frame, 0));
+    if magic != MAGIC {
+        return error(BadMagic(magic), 0);
+    }
+    if frame.len() < 6 {
+        return error(Truncated, 4);
+    }
+
+    let flg = frame[4];
+    if flg >> 6 != 1 {
+        return error(UnsupportedVersion(flg >> 6), 4);
+    }
+    if flg & 0x02 != 0 {
+        return error(ReservedBits, 4);
+    }
+
+    let bd = frame[5];
+    if bd & 0x8F != 0 {
+        return error(ReservedBits, 5);
+    }
+    let size_code = (bd >> 4) & 0x07;
+    if size_code < 4 {
+        return error(BadBlockMaxSize(size_code), 5);
+    }
+
+    let mut pos = 6;
+    let content_size = if flg & 0x08 != 0 {
+        let lo = try!(read32(frame, pos)) as u64;
+        let hi = try!(read32(frame, pos + 4)) as u64;
+        pos += 8;
+        Some(lo | hi << 32)
+    } else {
+        None
+    };
+    let dict_id = if flg & 0x01 != 0 {
+        let id = try!(read32(frame, pos));
+        pos += 4;
+        Some(id)
+    } else {
+        None
+    };
+
+    if frame.len() <= pos {
+        return error(Truncated, pos);
+    }
+    let stored = frame[pos];
+    let computed = (xxh32::oneshot(frame.slice(4, pos), 0) >> 8) as u8;
+    if stored != computed {
+        return error(HeaderChecksum { stored: stored, computed: computed }, pos);
+    }
+
+    Ok(FrameDescriptor {
+        block_independence: flg & 0x20 != 0,
+        block_checksum: flg & 0x10 != 0,
+        content_checksum: flg & 0x04 != 0,
+        content_size: content_size,
+        dict_id: dict_id,
+        block_max_size: 1 << (8 + 2 * size_code as usize),
+        header_len: pos + 1,
+    })
+}
+
+/// The total length of a skippable frame, or `None` if `frame` doesn't
+/// start with one.
+pub fn skippable_len(frame: &[u8]) -> Option<usize> {
+    if frame.len() < 8 || le32(frame, 0) & SKIPPABLE_MASK != SKIPPABLE_MAGIC {
+        return None;
+    }
+    Some(8 + le32(frame, 4) as usize)
+}
+
+/// A data block as stored in the frame.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct Block<'a> {
+    /// Offset of the block's size field.
+    pub offset: usize,
+    pub data: &'a [u8],
+    /// `false` for blocks stored uncompressed.
+    pub compressed: bool,
+    pub checksum: Option<u32>,
+}
+
+impl<'a> Block<'a> {
+    /// Check the block checksum, if the frame has them.
+    pub fn verify(&self) -> Result<(), Error> {
+        let stored = match self.checksum {
+            Some(stored) => stored,
+            None => return Ok(()),
+        };
+        let computed = xxh32::oneshot(self.data, 0);
+        if stored != computed {
+            return error(BlockChecksum { stored: stored, computed: computed },
+                         self.offset + 4 + self.data.len());
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over the blocks of a frame. Stops at the end mark, or after
+/// the first error.
+pub struct Blocks<'a> {
+    frame: &'a [u8],
+    descriptor: FrameDescriptor,
+    pos: usize,
+    end: Option<usize>,
+    failed: bool,
+}
+
+impl<'a> Blocks<'a> {
+    pub fn new(frame: &'a [u8]) -> Result<Blocks<'a>, Error> {
+        let descriptor = try!(parse_header(frame));
+        Ok(Blocks {
+            frame: frame,
+            descriptor: descriptor,
+            pos: descriptor.header_len,
+            end: None,
+            failed: false,
+        })
+    }
+
+    pub fn descriptor(&self) -> &FrameDescriptor { #![inline]
+        &self.descriptor
+    }
+
+    /// Offset just past the end mark, once it has been reached.
+    pub fn end(&self) -> Option<usize> { #![inline]
+        self.end
+    }
+
+    fn next_block(&mut self) -> Result<Option<Block<'a>>, Error> {
+        let offset = self.pos;
+        let word = try!(read32(self.frame, offset));
+        if word == 0 {
+            self.end = Some(offset + 4);
+            return Ok(None);
+        }
+
+        let size = (word & 0x7FFFFFFF) as usize;
+        if size > self.descriptor.block_max_size {
+            return error(BlockTooLarge(size), offset);
+        }
+        let start = offset + 4;
+        if self.frame.len() < start + size {
+            return error(Truncated, start);
+        }
+        let mut next = start + size;
+        let checksum = if self.descriptor.block_checksum {
+            let checksum = try!(read32(self.frame, next));
+            next += 4;
+            Some(checksum)
+        } else {
+            None
+        };
+
+        self.pos = next;
+        Ok(Some(Block {
+            offset: offset,
+            data: self.frame.slice(start, start + size),
+            compressed: word & 0x80000000 == 0,
+            checksum: checksum,
+        }))
+    }
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = Result<Block<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<Block<'a>, Error>> {
+        if self.end.is_some() || self.failed {
+            return None;
+        }
+        match self.next_block() {
+            Ok(Some(block)) => Some(Ok(block)),
+            Ok(None) => None,
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// What `verify_frame` found.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct FrameInfo {
+    pub descriptor: FrameDescriptor,
+    pub blocks: usize,
+    /// The stored content checksum and its offset, if the frame has one.
+    pub content_checksum: Option<(u32, usize)>,
+    /// Length of the whole frame; the next frame, if any, starts here.
+    pub frame_len: usize,
+}
+
+/// Check the header checksum and every block checksum of the frame at
+/// the start of `frame`.
+pub fn verify_frame(frame: &[u8]) -> Result<FrameInfo, Error> {
+    let mut blocks = try!(Blocks::new(frame));
+    let mut count = 0;
+    for block in blocks.by_ref() {
+        try!(try!(block).verify());
+        count += 1;
+    }
+
+    let descriptor = *blocks.descriptor();
+    let mut frame_len = blocks.end().unwrap();
+    let content_checksum = if descriptor.content_checksum {
+        let stored = try!(read32(frame, frame_len));
+        frame_len += 4;
+        Some((stored, frame_len - 4))
+    } else {
+        None
+    };
+
+    Ok(FrameInfo {
+        descriptor: descriptor,
+        blocks: count,
+        content_checksum: content_checksum,
+        frame_len: frame_len,
+    })
+}
+
+/// Checks decompressed output against the frame's content checksum as it
+/// streams past.
+#[derive(Copy, Clone)]
+pub struct ContentVerifier {
+    state: xxh32::XXHasher,
+    stored: u32,
+    offset: usize,
+}
+
+impl ContentVerifier {
+    /// `None` if the frame has no content checksum.
+    pub fn new(info: &FrameInfo) -> Option<ContentVerifier> {
+        info.content_checksum.map(|(stored, offset)| ContentVerifier {
+            state: xxh32::XXHasher::new_with_seed(0),
+            stored: stored,
+            offset: offset,
+        })
+    }
+
+    pub fn update(&mut self, decompressed: &[u8]) { #![inline]
+        self.state.write(decompressed)
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        let computed = self.state.finish();
+        if computed != self.stored {
+            return error(ContentChecksum { stored: self.stored, computed: computed },
+                         self.offset);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+static CONTENT: &'static [u8] = b"hello hello hello hello hello hello hello xxhash";
+
+// `lz4 --content-size -BX -BD`, with block and content checksums
+#[cfg(test)]
+static FRAME: [u8; 48] = [
+    0x04, 0x22, 0x4d, 0x18, 0x7c, 0x40, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0xcf, 0x11, 0x00, 0x00, 0x00, 0x6f, 0x68, 0x65, 0x6c, 0x6c,
+    0x6f, 0x20, 0x06, 0x00, 0x11, 0x60, 0x78, 0x78, 0x68, 0x61, 0x73, 0x68,
+    0x2a, 0x54, 0x1e, 0x57, 0x00, 0x00, 0x00, 0x00, 0x47, 0xbf, 0x3d, 0x85,
+];
+
+#[test]
+fn test_verify_frame() {
+    let info = verify_frame(&FRAME).unwrap();
+    assert_eq!(info.blocks, 1);
+    assert_eq!(info.frame_len, FRAME.len());
+    assert_eq!(info.content_checksum, Some((0x853dbf47, 44)));
+    assert_eq!(info.descriptor.content_size, Some(CONTENT.len() as u64));
+    assert_eq!(info.descriptor.block_max_size, 64 * 1024);
+
+    let mut verifier = ContentVerifier::new(&info).unwrap();
+    for chunk in CONTENT.chunks(5) {
+        verifier.update(chunk);
+    }
+    assert_eq!(verifier.verify(), Ok(()));
+
+    let mut verifier = ContentVerifier::new(&info).unwrap();
+    verifier.update(CONTENT.slice_to(10));
+    assert_eq!(verifier.verify().unwrap_err().offset, 44);
+}
+
+#[test]
+fn test_header_checksum() {
+    // the smallest possible header, as written by `lz4` with default settings
+    let header = [0x04, 0x22, 0x4d, 0x18, 0x64, 0x40, 0xa7];
+    assert!(parse_header(&header).is_ok());
+
+    let bad = [0x04, 0x22, 0x4d, 0x18, 0x64, 0x40, 0xa8];
+    assert_eq!(parse_header(&bad),
+               Err(Error { kind: HeaderChecksum { stored: 0xa8, computed: 0xa7 }, offset: 6 }));
+}
+
+#[test]
+fn test_corruption() {
+    let mut frame = FRAME;
+    frame[20] ^= 1;
+    assert_eq!(verify_frame(&frame),
+               Err(Error { kind: BlockChecksum { stored: 0x571e542a, computed: xxh32::oneshot(frame.slice(19, 36), 0) },
+                           offset: 36 }));
+
+    assert_eq!(verify_frame(FRAME.slice_to(30)).unwrap_err(), Error { kind: Truncated, offset: 19 });
+    assert_eq!(verify_frame(FRAME.slice_to(46)).unwrap_err(), Error { kind: Truncated, offset: 44 });
+    assert_eq!(verify_frame(FRAME.slice_from(1)).unwrap_err().kind, BadMagic(0x7c184d22));
+}
+
+#[test]
+fn test_skippable() {
+    let frame = [0x5a, 0x2a, 0x4d, 0x18, 0x02, 0x00, 0x00, 0x00, 0xff, 0xff];
+    assert_eq!(skippable_len(&frame), Some(10));
+    assert_eq!(skippable_len(&FRAME), None);
+}
@@ -13,3 +13,19 @@ macro_rules! read_ptr(($p:ident, $rem:ident, $size:ty) => ({
     $p = mem::transmute(dp);
     data.to_le()
 }));
+
+// Hash a string literal, optionally with a seed. These expand to a call
+// to `oneshot` and are evaluated at run time, so the result can't be used
+// in a `const`, `static` or `match` pattern; `codegen` writes constants
+// for those from a build script.
+#[macro_export]
+macro_rules! xxh64 {
+    ($s:expr) => (xxh64!($s, 0));
+    ($s:expr, $seed:expr) => ($crate::oneshot($s.as_bytes(), $seed));
+}
+
+#[macro_export]
+macro_rules! xxh32 {
+    ($s:expr) => (xxh32!($s, 0));
+    ($s:expr, $seed:expr) => ($crate::xxh32::oneshot($s.as_bytes(), $seed));
+}
@@ -0,0 +1,454 @@
+//! Manifests of a directory tree's files, for audits: generate one now,
+//! compare the tree against it later.
+//!
+//! A manifest is text. The first line is `# xxhash manifest 1`, and each
+//! file gets a line
+//!
+//!     xxh64 size mtime path
+//!
+//! with the canonical hex xxh64 (seed 0) of the contents, the size in
+//! bytes, the modification time in milliseconds since the epoch, and the
+//! path relative to the root, `/`-separated. Paths are the raw bytes of
+//! the names, so every file gets its own line whatever the encoding. In
+//! the text, backslashes, newlines and carriage returns are escaped as
+//! `\\`, `\n` and `\r`, and other control characters and bytes that aren't
+//! part of valid UTF-8 as `\xHH`. Lines are in byte order of the paths.
+//!
+//! Only regular files are listed; symlinks are not followed.
+
+use core::prelude::*;
+use core::fmt;
+use core::iter::repeat;
+use core::str::from_utf8;
+
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map;
+use std::error::FromError;
+use std::io::{IoResult, IoError, FileType, Reader, Writer};
+use std::io::fs::File;
+use std::path::Path;
+use std::string::String;
+use std::vec::Vec;
+
+use canonical::Xxh64Digest;
+use io::hash_file;
+use pool;
+use walk::walk;
+
+#[cfg(test)] use std::io::TempDir;
+#[cfg(test)] use std::io::fs::{mkdir, rename, unlink};
+#[cfg(test)] use std::io::FilePermission;
+#[cfg(test)] use walk::write_file;
+
+const HEADER: &'static str = "# xxhash manifest 1";
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub enum Error {
+    Io(IoError),
+    /// The first line isn't a manifest header of this version.
+    BadHeader,
+    /// This line, counting from 1, doesn't parse or repeats a path.
+    Malformed(usize),
+}
+
+impl FromError<IoError> for Error {
+    fn from_error(err: IoError) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl fmt::String for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::BadHeader => write!(f, "not a manifest"),
+            Error::Malformed(line) => write!(f, "malformed manifest line {}", line),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct ManifestEntry {
+    /// Relative to the root, `/`-separated, as the name bytes on disk.
+    pub path: Vec<u8>,
+    pub size: u64,
+    /// Milliseconds since the epoch.
+    pub mtime: u64,
+    pub hash: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct Manifest {
+    /// By path.
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// What changed between two manifests. Everything is in path order.
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct Changes {
+    pub added: Vec<Vec<u8>>,
+    pub removed: Vec<Vec<u8>>,
+    /// Same path, different contents. A new mtime alone doesn't count.
+    pub modified: Vec<Vec<u8>>,
+    /// Removed and added paths with the same contents, as `(from, to)`.
+    pub moved: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+            && self.modified.is_empty() && self.moved.is_empty()
+    }
+}
+
+impl Manifest {
+    /// Hash every file under `root`, on `threads` threads (0 for one per
+    /// core).
+    pub fn generate(root: &Path, threads: usize) -> IoResult<Manifest> {
+        generate(root, threads, None)
+    }
+
+    /// A manifest of `root` as it is now, taking the hash of any file whose
+    /// size and mtime are what this manifest has instead of reading it.
+    /// Quicker, but blind to edits that keep both, such as two writes
+    /// within the filesystem's timestamp granularity.
+    pub fn update(&self, root: &Path, threads: usize) -> IoResult<Manifest> {
+        generate(root, threads, Some(self))
+    }
+
+    pub fn get(&self, path: &[u8]) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|e| e.path.as_slice() == path)
+    }
+
+    /// Compare `self`, the old state, with `new`. A removed file and an
+    /// added one with the same size and hash are a move; with several
+    /// candidates, they pair up in path order. Empty files never move.
+    pub fn diff(&self, new: &Manifest) -> Changes {
+        let mut changes = Changes { added: Vec::new(), removed: Vec::new(), modified: Vec::new(), moved: Vec::new() };
+        let old_paths: BTreeMap<&[u8], &ManifestEntry> =
+            self.entries.iter().map(|e| (e.path.as_slice(), e)).collect();
+        let new_paths: BTreeMap<&[u8], &ManifestEntry> =
+            new.entries.iter().map(|e| (e.path.as_slice(), e)).collect();
+
+        let mut gone = Vec::new();
+        for old in self.entries.iter() {
+            match new_paths.get(old.path.as_slice()) {
+                Some(now) => if now.size != old.size || now.hash != old.hash {
+                    changes.modified.push(old.path.clone());
+                },
+                None => gone.push(old),
+            }
+        }
+
+        // the removed files by contents, the first path last, to pop
+        let mut sources: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+        for (i, old) in gone.iter().enumerate().rev() {
+            if old.size == 0 {
+                continue;
+            }
+            match sources.entry((old.size, old.hash)) {
+                hash_map::Entry::Occupied(mut e) => e.get_mut().push(i),
+                hash_map::Entry::Vacant(e) => { e.insert(vec![i]); }
+            }
+        }
+        let mut was_moved: Vec<bool> = repeat(false).take(gone.len()).collect();
+        for now in new.entries.iter() {
+            if old_paths.contains_key(now.path.as_slice()) {
+                continue;
+            }
+            let source = match sources.get_mut(&(now.size, now.hash)) {
+                Some(candidates) => candidates.pop(),
+                None => None,
+            };
+            match source {
+                Some(i) => {
+                    was_moved[i] = true;
+                    changes.moved.push((gone[i].path.clone(), now.path.clone()));
+                }
+                None => changes.added.push(now.path.clone()),
+            }
+        }
+        for (old, moved) in gone.iter().zip(was_moved.iter()) {
+            if !*moved {
+                changes.removed.push(old.path.clone());
+            }
+        }
+        changes
+    }
+
+    pub fn write<W: Writer>(&self, out: &mut W) -> IoResult<()> {
+        try!(write!(out, "{}\n", HEADER));
+        for e in self.entries.iter() {
+            try!(write!(out, "{} {} {} {}\n", Xxh64Digest(e.hash), e.size, e.mtime,
+                        escape_path(e.path.as_slice())));
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let mut file = try!(File::create(path));
+        try!(self.write(&mut file));
+        file.fsync()
+    }
+
+    pub fn parse(text: &str) -> Result<Manifest, Error> {
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(Error::BadHeader);
+        }
+        let mut entries = BTreeMap::new();
+        for (i, line) in lines.enumerate() {
+            let entry = match parse_line(line) {
+                Some(entry) => entry,
+                None => return Err(Error::Malformed(i + 2)),
+            };
+            if entries.contains_key(&entry.path) {
+                return Err(Error::Malformed(i + 2));
+            }
+            entries.insert(entry.path.clone(), entry);
+        }
+        Ok(Manifest { entries: entries.into_iter().map(|(_, e)| e).collect() })
+    }
+
+    pub fn load(path: &Path) -> Result<Manifest, Error> {
+        let text = try!(try!(File::open(path)).read_to_string());
+        Manifest::parse(text.as_slice())
+    }
+}
+
+fn parse_line(line: &str) -> Option<ManifestEntry> {
+    let mut rest = line;
+    let hash = match next_field(&mut rest).and_then(|f| f.parse::<Xxh64Digest>()) {
+        Some(hash) => hash.0,
+        None => return None,
+    };
+    let size = match next_field(&mut rest).and_then(|f| f.parse()) {
+        Some(size) => size,
+        None => return None,
+    };
+    let mtime = match next_field(&mut rest).and_then(|f| f.parse()) {
+        Some(mtime) => mtime,
+        None => return None,
+    };
+    if rest.is_empty() {
+        return None;
+    }
+    unescape_path(rest).map(|path| ManifestEntry { path: path, size: size, mtime: mtime, hash: hash })
+}
+
+// The text up to the next space, which is skipped.
+fn next_field<'a>(rest: &mut &'a str) -> Option<&'a str> {
+    let i = match rest.find(' ') {
+        Some(i) => i,
+        None => return None,
+    };
+    let field = rest.slice_to(i);
+    *rest = rest.slice_from(i + 1);
+    Some(field)
+}
+
+/// `path` as a manifest line spells it, which is also a fair way to show
+/// it to a person.
+pub fn escape_path(path: &[u8]) -> String {
+    let mut out = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        // whole UTF-8 sequences go as they are
+        let len = match path[i] { 0xc0...0xdf => 2, 0xe0...0xef => 3, 0xf0...0xf7 => 4, _ => 1 };
+        if len > 1 && i + len <= path.len() {
+            if let Ok(c) = from_utf8(path.slice(i, i + len)) {
+                out.push_str(c);
+                i += len;
+                continue;
+            }
+        }
+        match path[i] {
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b @ 0x20...0x7e => out.push(b as char),
+            b => out.push_str(format!("\\x{:02x}", b).as_slice()),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// The path `escape_path` turned into `s`, or `None` for a bad escape.
+pub fn unescape_path(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes.get(i + 1).map(|b| *b) {
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'n') => out.push(b'\n'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b'x') if i + 4 <= bytes.len() => {
+                match (hex_digit(bytes[i + 2]), hex_digit(bytes[i + 3])) {
+                    (Some(hi), Some(lo)) => out.push(hi << 4 | lo),
+                    _ => return None,
+                }
+                i += 2;
+            }
+            _ => return None,
+        }
+        i += 2;
+    }
+    Some(out)
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'...b'9' => Some(c - b'0'),
+        b'a'...b'f' => Some(c - b'a' + 10),
+        b'A'...b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+// A file the walk found, and its hash if `previous` vouches for it.
+struct Found {
+    path: Vec<u8>,
+    full: Path,
+    size: u64,
+    mtime: u64,
+    known: Option<u64>,
+}
+
+fn generate(root: &Path, threads: usize, previous: Option<&Manifest>) -> IoResult<Manifest> {
+    let mut found: Vec<Found> = try!(walk(root, |_| false)).into_iter()
+        .filter(|entry| entry.stat.kind == FileType::RegularFile)
+        .map(|entry| Found {
+            size: entry.stat.size,
+            mtime: entry.stat.modified,
+            path: entry.path,
+            full: entry.full,
+            known: None,
+        })
+        .collect();
+
+    if let Some(previous) = previous {
+        let before: BTreeMap<&[u8], &ManifestEntry> =
+            previous.entries.iter().map(|e| (e.path.as_slice(), e)).collect();
+        for file in found.iter_mut() {
+            if let Some(e) = before.get(file.path.as_slice()) {
+                if file.size == e.size && file.mtime == e.mtime {
+                    file.known = Some(e.hash);
+                }
+            }
+        }
+    }
+
+    let hashes = pool::map(found.as_slice(), threads, |file| {
+        match file.known {
+            Some(hash) => Ok(hash),
+            None => hash_file(&file.full, 0),
+        }
+    });
+
+    let mut entries = Vec::with_capacity(found.len());
+    for (file, hash) in found.into_iter().zip(hashes.into_iter()) {
+        let hash = try!(hash);
+        entries.push(ManifestEntry { path: file.path, size: file.size, mtime: file.mtime, hash: hash });
+    }
+    Ok(Manifest { entries: entries })
+}
+
+#[cfg(test)]
+fn bytes(s: &[u8]) -> Vec<u8> {
+    let mut v = Vec::with_capacity(s.len());
+    v.push_all(s);
+    v
+}
+
+#[test]
+fn test_text_roundtrip() {
+    let manifest = Manifest { entries: vec![
+        ManifestEntry { path: bytes(b"a b/c\\d"), size: 5, mtime: 1420070400000, hash: 0x123 },
+        ManifestEntry { path: bytes("caf\u{e9}".as_bytes()), size: 1, mtime: 2, hash: 3 },
+        ManifestEntry { path: bytes(b"caf\xe9\x7f"), size: 1, mtime: 2, hash: 3 },
+        ManifestEntry { path: bytes(b"line\nbreak"), size: 0, mtime: 0, hash: ::oneshot(b"", 0) },
+    ] };
+    let mut text = Vec::new();
+    manifest.write(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert_eq!(text.as_slice(), "# xxhash manifest 1\n\
+                                 0000000000000123 5 1420070400000 a b/c\\\\d\n\
+                                 0000000000000003 1 2 caf\u{e9}\n\
+                                 0000000000000003 1 2 caf\\xe9\\x7f\n\
+                                 ef46db3751d8e999 0 0 line\\nbreak\n");
+    assert_eq!(Manifest::parse(text.as_slice()), Ok(manifest));
+
+    assert_eq!(Manifest::parse(""), Err(Error::BadHeader));
+    assert_eq!(Manifest::parse("# xxhash manifest 1\n0000000000000123 5 x a\n"),
+               Err(Error::Malformed(2)));
+    assert_eq!(Manifest::parse("# xxhash manifest 1\n0000000000000123 5 0 a\\\n"),
+               Err(Error::Malformed(2)));
+    assert_eq!(Manifest::parse("# xxhash manifest 1\n0000000000000123 5 0 a\\xf\n"),
+               Err(Error::Malformed(2)));
+    assert_eq!(Manifest::parse("# xxhash manifest 1\n0000000000000123 5 0 a\n0000000000000123 5 0 a\n"),
+               Err(Error::Malformed(3)));
+}
+
+#[test]
+fn test_generate_and_diff() {
+    let dir = TempDir::new("xxhash-manifest").unwrap();
+    let root = dir.path();
+    mkdir(&root.join("sub"), FilePermission::from_bits_truncate(0o755)).unwrap();
+    write_file(&root.join("keep"), b"unchanged");
+    write_file(&root.join("edit"), b"before");
+    write_file(&root.join("sub/move"), b"going places");
+    write_file(&root.join("gone"), b"deleted");
+
+    let old = Manifest::generate(root, 0).unwrap();
+    let paths: Vec<&[u8]> = old.entries.iter().map(|e| e.path.as_slice()).collect();
+    assert_eq!(paths, vec![b"edit".as_slice(), b"gone".as_slice(), b"keep".as_slice(), b"sub/move".as_slice()]);
+    assert_eq!(old.get(b"keep").unwrap().hash, ::oneshot(b"unchanged", 0));
+    assert!(old.diff(&old).is_empty());
+
+    write_file(&root.join("edit"), b"after");
+    rename(&root.join("sub/move"), &root.join("moved")).unwrap();
+    unlink(&root.join("gone")).unwrap();
+    write_file(&root.join("new"), b"brand new");
+
+    let new = Manifest::generate(root, 1).unwrap();
+    let changes = old.diff(&new);
+    assert_eq!(changes.added, vec![bytes(b"new")]);
+    assert_eq!(changes.removed, vec![bytes(b"gone")]);
+    assert_eq!(changes.modified, vec![bytes(b"edit")]);
+    assert_eq!(changes.moved, vec![(bytes(b"sub/move"), bytes(b"moved"))]);
+
+    // a stale hash survives an update as long as size and mtime match
+    let mut stale = new.clone();
+    stale.entries[0].hash ^= 1;
+    let updated = stale.update(root, 0).unwrap();
+    assert_eq!(updated.entries[0].hash, stale.entries[0].hash);
+    stale.entries[0].mtime ^= 1;
+    assert_eq!(stale.update(root, 0).unwrap(), new);
+}
+
+#[test]
+fn test_non_utf8_paths() {
+    let dir = TempDir::new("xxhash-manifest").unwrap();
+    let root = dir.path();
+    // both are "a\u{fffd}" once made UTF-8
+    write_file(&root.join(b"a\xfe".as_slice()), b"one");
+    write_file(&root.join(b"a\xff".as_slice()), b"two");
+
+    let old = Manifest::generate(root, 0).unwrap();
+    assert_eq!(old.entries.len(), 2);
+    let mut text = Vec::new();
+    old.write(&mut text).unwrap();
+    let old = Manifest::parse(String::from_utf8(text).unwrap().as_slice()).unwrap();
+    assert_eq!(old.get(b"a\xff").unwrap().hash, ::oneshot(b"two", 0));
+
+    write_file(&root.join(b"a\xfe".as_slice()), b"changed");
+    let changes = old.diff(&Manifest::generate(root, 0).unwrap());
+    assert_eq!(changes.modified, vec![bytes(b"a\xfe")]);
+}
@@ -0,0 +1,219 @@
+//! Merkle trees over fixed-size leaves, so one large input can be hashed
+//! on several cores and checked a piece at a time.
+//!
+//! Leaves are hashed with xxh64 under `LEAF_SEED`; each internal node is
+//! the xxh64, under `NODE_SEED`, of its children's hashes as two
+//! little-endian u64s. The distinct seeds keep a leaf from passing for a
+//! node. A node without a sibling moves up a level unchanged.
+//!
+//! Only xxh64 is available: the crate has no XXH128.
+
+use core::prelude::*;
+use core::cmp::max;
+use core::iter::repeat;
+
+use std::os::num_cpus;
+use std::thread::{Thread, JoinGuard};
+use std::vec::Vec;
+
+use bytes::put_le64;
+
+#[cfg(test)] use core::iter::range;
+
+/// "leaf"
+pub const LEAF_SEED: u64 = 0x6c656166;
+/// "node"
+pub const NODE_SEED: u64 = 0x6e6f6465;
+
+pub fn leaf_hash(leaf: &[u8]) -> u64 { #![inline]
+    ::oneshot(leaf, LEAF_SEED)
+}
+
+pub fn node_hash(left: u64, right: u64) -> u64 { #![inline]
+    let mut buf = [0u8; 16];
+    put_le64(&mut buf, 0, left);
+    put_le64(&mut buf, 8, right);
+    ::oneshot(&buf, NODE_SEED)
+}
+
+/// The root of `data` split into `leaf_size` leaves, hashed on all cores.
+pub fn root(data: &[u8], leaf_size: usize) -> u64 {
+    MerkleTree::new(data, leaf_size).root()
+}
+
+pub struct MerkleTree {
+    leaf_size: usize,
+    // leaves first, the root last
+    levels: Vec<Vec<u64>>,
+}
+
+impl MerkleTree {
+    /// Build the tree of `data` using one thread per core.
+    pub fn new(data: &[u8], leaf_size: usize) -> MerkleTree {
+        MerkleTree::build(data, leaf_size, num_cpus())
+    }
+
+    /// Build the tree of `data`, hashing the leaves on up to `threads`
+    /// threads. Empty input is a single empty leaf.
+    ///
+    /// Panics if `leaf_size` is zero.
+    pub fn build(data: &[u8], leaf_size: usize, threads: usize) -> MerkleTree {
+        assert!(leaf_size > 0);
+        let count = max(1, (data.len() + leaf_size - 1) / leaf_size);
+        let mut leaves: Vec<u64> = repeat(0).take(count).collect();
+
+        // each thread takes a run of whole leaves
+        let per_thread = (count + max(1, threads) - 1) / max(1, threads);
+        if data.len() <= leaf_size || per_thread == count {
+            for (h, leaf) in leaves.iter_mut().zip(chunks_or_empty(data, leaf_size)) {
+                *h = leaf_hash(leaf);
+            }
+        } else {
+            // the guards join when dropped, passing on any panic
+            let _guards: Vec<JoinGuard<()>> = leaves.chunks_mut(per_thread)
+                .zip(data.chunks(per_thread * leaf_size))
+                .map(|(out, input)| Thread::scoped(move || {
+                    for (h, leaf) in out.iter_mut().zip(input.chunks(leaf_size)) {
+                        *h = leaf_hash(leaf);
+                    }
+                }))
+                .collect();
+        }
+
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels.last().unwrap().chunks(2).map(|pair| {
+                if pair.len() == 2 { node_hash(pair[0], pair[1]) } else { pair[0] }
+            }).collect();
+            levels.push(next);
+        }
+        MerkleTree { leaf_size: leaf_size, levels: levels }
+    }
+
+    pub fn root(&self) -> u64 { #![inline]
+        self.levels.last().unwrap()[0]
+    }
+
+    pub fn leaf_size(&self) -> usize { #![inline]
+        self.leaf_size
+    }
+
+    pub fn leaf_count(&self) -> u64 { #![inline]
+        self.levels[0].len() as u64
+    }
+
+    pub fn leaves(&self) -> &[u64] { #![inline]
+        self.levels[0].as_slice()
+    }
+
+    /// The proof that leaf `index` is part of this tree.
+    pub fn proof(&self, index: u64) -> Option<Proof> {
+        if index >= self.leaf_count() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let mut i = index as usize;
+        for level in self.levels.init().iter() {
+            if let Some(sibling) = level.get(i ^ 1) {
+                siblings.push(*sibling);
+            }
+            i >>= 1;
+        }
+        Some(Proof { index: index, leaf_count: self.leaf_count(), siblings: siblings })
+    }
+}
+
+// `chunks` yields nothing for empty input, but the tree has an empty leaf.
+fn chunks_or_empty<'a>(data: &'a [u8], leaf_size: usize) -> Vec<&'a [u8]> {
+    if data.is_empty() { vec![data] } else { data.chunks(leaf_size).collect() }
+}
+
+/// The sibling hashes on the path from one leaf to the root.
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct Proof {
+    pub index: u64,
+    pub leaf_count: u64,
+    /// Bottom up, skipping the levels where the path has no sibling.
+    pub siblings: Vec<u64>,
+}
+
+impl Proof {
+    /// The root this proof leads to from a leaf with hash `leaf`, or
+    /// `None` if the proof doesn't fit its own index and leaf count.
+    pub fn root_from(&self, leaf: u64) -> Option<u64> {
+        if self.index >= self.leaf_count {
+            return None;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut h = leaf;
+        let mut i = self.index;
+        let mut n = self.leaf_count;
+        while n > 1 {
+            if i ^ 1 < n {
+                let sibling = match siblings.next() { Some(s) => *s, None => return None };
+                h = if i & 1 == 0 { node_hash(h, sibling) } else { node_hash(sibling, h) };
+            }
+            i >>= 1;
+            n = (n + 1) / 2;
+        }
+        if siblings.next().is_some() {
+            return None;
+        }
+        Some(h)
+    }
+
+    /// Whether `leaf`, the bytes of leaf `self.index`, belongs to the tree
+    /// with root `root`.
+    pub fn verify(&self, root: u64, leaf: &[u8]) -> bool {
+        self.root_from(leaf_hash(leaf)) == Some(root)
+    }
+}
+
+#[test]
+fn test_shape() {
+    // five leaves: ((0 1) (2 3)) 4
+    let data: Vec<u8> = range(0, 5 * 64 - 10).map(|i| i as u8).collect();
+    let leaf = |i: usize| leaf_hash(data.slice(64 * i, ::core::cmp::min(64 * (i + 1), data.len())));
+    let expected = node_hash(node_hash(node_hash(leaf(0), leaf(1)), node_hash(leaf(2), leaf(3))),
+                             leaf(4));
+    let tree = MerkleTree::build(data.as_slice(), 64, 1);
+    assert_eq!(tree.leaf_count(), 5);
+    assert_eq!(tree.root(), expected);
+
+    // the seeds keep a single leaf from looking like anything else
+    assert_eq!(root(&[], 64), leaf_hash(&[]));
+    assert!(leaf_hash(&[]) != ::oneshot(&[], 0));
+    assert_eq!(root(b"abc", 64), leaf_hash(b"abc"));
+}
+
+#[test]
+fn test_parallel() {
+    let data: Vec<u8> = range(0, 1000 * 100 + 17).map(|i| (i * 31 >> 3) as u8).collect();
+    let serial = MerkleTree::build(data.as_slice(), 100, 1);
+    for &threads in [2, 3, 8, 2000].iter() {
+        let tree = MerkleTree::build(data.as_slice(), 100, threads);
+        assert_eq!(tree.leaves(), serial.leaves());
+        assert_eq!(tree.root(), serial.root());
+    }
+}
+
+#[test]
+fn test_proofs() {
+    let data: Vec<u8> = range(0, 7 * 32).map(|i| i as u8).collect();
+    let tree = MerkleTree::new(data.as_slice(), 32);
+    for i in range(0, 7) {
+        let proof = tree.proof(i).unwrap();
+        let leaf = data.slice(32 * i as usize, 32 * (i as usize + 1));
+        assert!(proof.verify(tree.root(), leaf));
+
+        // wrong data, wrong position, wrong tree
+        assert!(!proof.verify(tree.root(), data.slice_to(31)));
+        assert!(!proof.verify(tree.root() ^ 1, leaf));
+        let mut moved = proof.clone();
+        moved.index = (i + 1) % 7;
+        assert!(!moved.verify(tree.root(), leaf));
+    }
+    // the last leaf skips the level where it has no sibling
+    assert_eq!(tree.proof(6).unwrap().siblings.len(), 2);
+    assert!(tree.proof(7).is_none());
+}
@@ -0,0 +1,358 @@
+//! Self-describing [multihash](https://multiformats.io/multihash/)
+//! encoding of xxHash digests: `varint(code) ++ varint(length) ++ digest`,
+//! with the digest in its canonical big-endian form.
+//!
+//! The multicodec table assigns codes to all four xxHash variants, but
+//! only xxh-32 and xxh-64 are implemented here. Multihashes with the xxh3
+//! codes still parse, so they can be stored and compared, but nothing
+//! here computes them: `AnyHasher::from_code` rejects those codes as
+//! `Unsupported`.
+
+use core::prelude::*;
+use core::fmt;
+use core::hash::{Hasher, Writer};
+use core::ops::Deref;
+
+use canonical::{Xxh32Digest, Xxh64Digest};
+use XXHasher;
+use xxh32;
+
+use self::Error::*;
+
+// three bytes of code, one of length, at most 16 of digest
+const MAX_LEN: usize = 20;
+
+// the multiformats spec caps varints at nine bytes, i.e. 63 bits
+const MAX_VARINT_LEN: usize = 9;
+
+/// The multicodec table entries for xxHash.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum Code {
+    Xxh32,
+    Xxh64,
+    Xxh3_64,
+    Xxh3_128,
+}
+
+impl Code {
+    pub fn from_u64(code: u64) -> Option<Code> {
+        match code {
+            0xb3e1 => Some(Code::Xxh32),
+            0xb3e2 => Some(Code::Xxh64),
+            0xb3e3 => Some(Code::Xxh3_64),
+            0xb3e4 => Some(Code::Xxh3_128),
+            _ => None,
+        }
+    }
+
+    pub fn to_u64(self) -> u64 {
+        match self {
+            Code::Xxh32 => 0xb3e1,
+            Code::Xxh64 => 0xb3e2,
+            Code::Xxh3_64 => 0xb3e3,
+            Code::Xxh3_128 => 0xb3e4,
+        }
+    }
+
+    /// Size of the digest in bytes.
+    pub fn digest_len(self) -> usize {
+        match self {
+            Code::Xxh32 => 4,
+            Code::Xxh64 | Code::Xxh3_64 => 8,
+            Code::Xxh3_128 => 16,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum Error {
+    /// The input ended inside a varint or the digest.
+    Truncated,
+    /// A varint longer than nine bytes or not minimally encoded.
+    BadVarint,
+    UnknownCode(u64),
+    /// An xxHash variant this crate doesn't implement.
+    Unsupported(Code),
+    WrongCode { expected: Code, found: Code },
+    WrongLength { expected: usize, found: u64 },
+    TrailingBytes,
+}
+
+impl fmt::String for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Truncated => write!(f, "multihash is truncated"),
+            BadVarint => write!(f, "malformed varint"),
+            UnknownCode(c) => write!(f, "unknown multihash code {:#x}", c),
+            Unsupported(c) => write!(f, "{:?} is not supported", c),
+            WrongCode { expected, found } =>
+                write!(f, "expected a {:?} multihash, found {:?}", expected, found),
+            WrongLength { expected, found } =>
+                write!(f, "expected a {} byte digest, found {}", expected, found),
+            TrailingBytes => write!(f, "trailing bytes after the digest"),
+        }
+    }
+}
+
+/// An encoded multihash. Dereferences to the encoded bytes.
+#[derive(Copy)]
+pub struct Multihash {
+    code: Code,
+    buf: [u8; MAX_LEN],
+    len: usize,
+    digest_start: usize,
+}
+
+impl Multihash {
+    fn new(code: Code, digest: &[u8]) -> Multihash {
+        debug_assert_eq!(digest.len(), code.digest_len());
+
+        let mut buf = [0u8; MAX_LEN];
+        let mut len = put_varint(&mut buf, 0, code.to_u64());
+        len = put_varint(&mut buf, len, digest.len() as u64);
+        let digest_start = len;
+        for &b in digest.iter() {
+            buf[len] = b;
+            len += 1;
+        }
+        Multihash { code: code, buf: buf, len: len, digest_start: digest_start }
+    }
+
+    /// Parse and validate an encoded multihash. The code must be one of
+    /// the xxHash codes, the length must match it, and nothing may follow
+    /// the digest.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Multihash, Error> {
+        let (raw_code, n) = try!(get_varint(bytes));
+        let code = match Code::from_u64(raw_code) {
+            Some(code) => code,
+            None => return Err(UnknownCode(raw_code)),
+        };
+        let (len, m) = try!(get_varint(bytes.slice_from(n)));
+        if len != code.digest_len() as u64 {
+            return Err(WrongLength { expected: code.digest_len(), found: len });
+        }
+
+        let digest = bytes.slice_from(n + m);
+        if digest.len() < code.digest_len() {
+            return Err(Truncated);
+        }
+        if digest.len() > code.digest_len() {
+            return Err(TrailingBytes);
+        }
+        Ok(Multihash::new(code, digest))
+    }
+
+    pub fn code(&self) -> Code { #![inline]
+        self.code
+    }
+
+    /// The raw digest, without the prefix.
+    pub fn digest(&self) -> &[u8] { #![inline]
+        self.buf.slice(self.digest_start, self.len)
+    }
+
+    pub fn as_slice(&self) -> &[u8] { #![inline]
+        self.buf.slice_to(self.len)
+    }
+}
+
+impl Clone for Multihash {
+    fn clone(&self) -> Multihash { #![inline]
+        *self
+    }
+}
+
+impl PartialEq for Multihash {
+    fn eq(&self, other: &Multihash) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Multihash {}
+
+impl fmt::Show for Multihash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "Multihash({:?}, ", self.code));
+        for b in self.digest().iter() {
+            try!(write!(f, "{:02x}", *b));
+        }
+        write!(f, ")")
+    }
+}
+
+impl Deref for Multihash {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] { #![inline]
+        self.as_slice()
+    }
+}
+
+impl Xxh32Digest {
+    pub fn to_multihash(&self) -> Multihash {
+        Multihash::new(Code::Xxh32, &self.to_be_bytes())
+    }
+
+    pub fn from_multihash(bytes: &[u8]) -> Result<Xxh32Digest, Error> {
+        let mh = try!(expect(bytes, Code::Xxh32));
+        let d = mh.digest();
+        Ok(Xxh32Digest::from_be_bytes([d[0], d[1], d[2], d[3]]))
+    }
+}
+
+impl Xxh64Digest {
+    pub fn to_multihash(&self) -> Multihash {
+        Multihash::new(Code::Xxh64, &self.to_be_bytes())
+    }
+
+    pub fn from_multihash(bytes: &[u8]) -> Result<Xxh64Digest, Error> {
+        let mh = try!(expect(bytes, Code::Xxh64));
+        let d = mh.digest();
+        Ok(Xxh64Digest::from_be_bytes([d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]]))
+    }
+}
+
+fn expect(bytes: &[u8], code: Code) -> Result<Multihash, Error> {
+    let mh = try!(Multihash::from_bytes(bytes));
+    if mh.code() != code {
+        return Err(WrongCode { expected: code, found: mh.code() });
+    }
+    Ok(mh)
+}
+
+/// A streaming hasher that knows its multihash code. Multihashes are
+/// always computed with seed 0.
+pub trait Multihasher {
+    fn code(&self) -> Code;
+    fn update(&mut self, input: &[u8]);
+    fn multihash(&self) -> Multihash;
+}
+
+/// The hasher for a multicodec code picked at runtime, always seeded
+/// with 0. The bare hashers don't implement `Multihasher`: they can carry
+/// any seed, and a multihash of another seed matches nothing else.
+#[derive(Copy, Clone)]
+pub enum AnyHasher {
+    Xxh32(xxh32::XXHasher),
+    Xxh64(XXHasher),
+}
+
+impl AnyHasher {
+    pub fn from_code(code: u64) -> Result<AnyHasher, Error> {
+        match Code::from_u64(code) {
+            Some(Code::Xxh32) => Ok(AnyHasher::Xxh32(xxh32::XXHasher::new_with_seed(0))),
+            Some(Code::Xxh64) => Ok(AnyHasher::Xxh64(XXHasher::new_with_seed(0))),
+            Some(other) => Err(Unsupported(other)),
+            None => Err(UnknownCode(code)),
+        }
+    }
+}
+
+impl Multihasher for AnyHasher {
+    fn code(&self) -> Code {
+        match *self {
+            AnyHasher::Xxh32(_) => Code::Xxh32,
+            AnyHasher::Xxh64(_) => Code::Xxh64,
+        }
+    }
+
+    fn update(&mut self, input: &[u8]) {
+        match *self {
+            AnyHasher::Xxh32(ref mut h) => Writer::write(h, input),
+            AnyHasher::Xxh64(ref mut h) => Writer::write(h, input),
+        }
+    }
+
+    fn multihash(&self) -> Multihash {
+        match *self {
+            AnyHasher::Xxh32(ref h) => Xxh32Digest(h.finish()).to_multihash(),
+            AnyHasher::Xxh64(ref h) => Xxh64Digest(h.finish()).to_multihash(),
+        }
+    }
+}
+
+// Unsigned LEB128. Returns the new end of `buf`.
+fn put_varint(buf: &mut [u8], mut pos: usize, mut value: u64) -> usize {
+    while value >= 0x80 {
+        buf[pos] = (value as u8) | 0x80;
+        value >>= 7;
+        pos += 1;
+    }
+    buf[pos] = value as u8;
+    pos + 1
+}
+
+// Returns the value and the number of bytes it took.
+fn get_varint(bytes: &[u8]) -> Result<(u64, usize), Error> {
+    let mut value = 0u64;
+    for (i, &b) in bytes.iter().enumerate() {
+        if i == MAX_VARINT_LEN {
+            return Err(BadVarint);
+        }
+        value |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            // a zero high byte means a shorter encoding existed
+            if b == 0 && i > 0 {
+                return Err(BadVarint);
+            }
+            return Ok((value, i + 1));
+        }
+    }
+    Err(Truncated)
+}
+
+#[test]
+fn test_multihash_roundtrip() {
+    let d64 = Xxh64Digest(::oneshot(b"", 0));
+    let mh = d64.to_multihash();
+    assert_eq!(mh.as_slice(),
+               [0xe2, 0xe7, 0x02, 0x08, 0xef, 0x46, 0xdb, 0x37, 0x51, 0xd8, 0xe9, 0x99].as_slice());
+    assert_eq!(Xxh64Digest::from_multihash(&*mh), Ok(d64));
+
+    let d32 = Xxh32Digest(::xxh32::oneshot(b"", 0));
+    let mh = d32.to_multihash();
+    assert_eq!(mh.as_slice(), [0xe1, 0xe7, 0x02, 0x04, 0x02, 0xcc, 0x5d, 0x05].as_slice());
+    assert_eq!(Xxh32Digest::from_multihash(&*mh), Ok(d32));
+}
+
+#[test]
+fn test_multihash_validation() {
+    let mh = Xxh64Digest(0xef46db3751d8e999).to_multihash();
+    let bytes = mh.as_slice();
+
+    assert_eq!(Xxh32Digest::from_multihash(bytes),
+               Err(WrongCode { expected: Code::Xxh32, found: Code::Xxh64 }));
+    assert_eq!(Multihash::from_bytes(bytes.slice_to(11)), Err(Truncated));
+    assert_eq!(Multihash::from_bytes(bytes.slice_to(2)), Err(Truncated));
+
+    let mut long = [0u8; 13];
+    for (d, s) in long.iter_mut().zip(bytes.iter()) { *d = *s; }
+    assert_eq!(Multihash::from_bytes(&long), Err(TrailingBytes));
+
+    assert_eq!(Multihash::from_bytes(&[0xe2, 0xe7, 0x02, 0x04, 0, 0, 0, 0]),
+               Err(WrongLength { expected: 8, found: 4 }));
+    assert_eq!(Multihash::from_bytes(&[0x12, 0x20]), Err(UnknownCode(0x12)));
+    assert_eq!(Multihash::from_bytes(&[0xe3, 0xe7, 0x02, 0x08, 0, 0, 0, 0, 0, 0, 0, 0]).map(|m| m.code()),
+               Ok(Code::Xxh3_64));
+    // non-minimal encoding of the length
+    assert_eq!(Multihash::from_bytes(&[0xe2, 0xe7, 0x02, 0x88, 0x00]), Err(BadVarint));
+}
+
+#[test]
+fn test_any_hasher() {
+    let data = b"Nobody inspects the spammish repetition";
+
+    let mut state = AnyHasher::from_code(0xb3e2).unwrap();
+    for chunk in data.chunks(7) {
+        state.update(chunk);
+    }
+    assert_eq!(state.code(), Code::Xxh64);
+    assert_eq!(state.multihash(), Xxh64Digest(::oneshot(data, 0)).to_multihash());
+
+    let mut state = AnyHasher::from_code(0xb3e1).unwrap();
+    state.update(data);
+    assert_eq!(state.multihash(), Xxh32Digest(::xxh32::oneshot(data, 0)).to_multihash());
+
+    assert_eq!(AnyHasher::from_code(0xb3e4).err(), Some(Unsupported(Code::Xxh3_128)));
+    assert_eq!(AnyHasher::from_code(0x12).err(), Some(UnknownCode(0x12)));
+}
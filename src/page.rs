@@ -0,0 +1,145 @@
+//! Checksums kept in a trailer at the end of fixed-size pages, as storage
+//! engines use to catch torn or rotten pages on read.
+//!
+//! The checksum covers the page up to the trailer and is seeded with the
+//! page number, so an intact page written to the wrong place fails too.
+//! The trailer holds the xxh32 or xxh64 little-endian.
+
+use core::prelude::*;
+use core::fmt;
+
+use bytes::{le32, le64, put_le32, put_le64};
+use Algorithm;
+
+#[cfg(test)] use core::iter::range;
+
+pub const MIN_PAGE_SIZE: usize = 512;
+pub const MAX_PAGE_SIZE: usize = 64 * 1024;
+
+/// A page that failed verification.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct PageError {
+    pub page_no: u64,
+    pub stored: u64,
+    pub computed: u64,
+}
+
+impl fmt::String for PageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "checksum mismatch in page {}: stored {:x}, computed {:x}",
+               self.page_no, self.stored, self.computed)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct PageChecksum {
+    algorithm: Algorithm,
+    page_size: usize,
+}
+
+impl PageChecksum {
+    /// `None` unless `page_size` is a power of two from `MIN_PAGE_SIZE` to
+    /// `MAX_PAGE_SIZE`.
+    pub fn new(algorithm: Algorithm, page_size: usize) -> Option<PageChecksum> {
+        if page_size < MIN_PAGE_SIZE || page_size > MAX_PAGE_SIZE
+            || page_size & (page_size - 1) != 0 {
+            return None;
+        }
+        Some(PageChecksum { algorithm: algorithm, page_size: page_size })
+    }
+
+    pub fn algorithm(&self) -> Algorithm { #![inline]
+        self.algorithm
+    }
+
+    pub fn page_size(&self) -> usize { #![inline]
+        self.page_size
+    }
+
+    pub fn trailer_len(&self) -> usize { #![inline]
+        self.algorithm.digest_len()
+    }
+
+    /// The bytes of a page that are left for the caller.
+    pub fn usable_len(&self) -> usize { #![inline]
+        self.page_size - self.trailer_len()
+    }
+
+    /// The checksum of `page`, without its trailer.
+    ///
+    /// Panics if `page` isn't exactly one page long.
+    pub fn compute(&self, page: &[u8], page_no: u64) -> u64 {
+        assert_eq!(page.len(), self.page_size);
+        let seed = match self.algorithm {
+            // fold, so pages 2^32 apart get different seeds
+            Algorithm::Xxh32 => page_no ^ (page_no >> 32),
+            Algorithm::Xxh64 => page_no,
+        };
+        self.algorithm.oneshot(page.slice_to(self.usable_len()), seed)
+    }
+
+    /// The checksum in the trailer of `page`.
+    pub fn stored(&self, page: &[u8]) -> u64 {
+        assert_eq!(page.len(), self.page_size);
+        match self.algorithm {
+            Algorithm::Xxh32 => le32(page, self.usable_len()) as u64,
+            Algorithm::Xxh64 => le64(page, self.usable_len()),
+        }
+    }
+
+    /// Fill in the trailer of `page`, which is about to be written out as
+    /// page `page_no`.
+    pub fn write(&self, page: &mut [u8], page_no: u64) {
+        let sum = self.compute(page, page_no);
+        let at = self.usable_len();
+        match self.algorithm {
+            Algorithm::Xxh32 => put_le32(page, at, sum as u32),
+            Algorithm::Xxh64 => put_le64(page, at, sum),
+        }
+    }
+
+    /// Check `page`, which was read back as page `page_no`.
+    pub fn verify(&self, page: &[u8], page_no: u64) -> Result<(), PageError> {
+        let stored = self.stored(page);
+        let computed = self.compute(page, page_no);
+        if stored != computed {
+            return Err(PageError { page_no: page_no, stored: stored, computed: computed });
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_page_sizes() {
+    assert!(PageChecksum::new(Algorithm::Xxh64, 256).is_none());
+    assert!(PageChecksum::new(Algorithm::Xxh64, 4000).is_none());
+    assert!(PageChecksum::new(Algorithm::Xxh64, 128 * 1024).is_none());
+    for shift in range(9, 17) {
+        let pages = PageChecksum::new(Algorithm::Xxh32, 1 << shift).unwrap();
+        assert_eq!(pages.usable_len(), (1 << shift) - 4);
+    }
+}
+
+#[test]
+fn test_write_verify() {
+    let pages = PageChecksum::new(Algorithm::Xxh64, 4096).unwrap();
+    let mut page = [0u8; 4096];
+    pages.write(&mut page, 7);
+    assert_eq!(le64(&page, 4088), 0xd103ae196183c265);
+    assert_eq!(pages.verify(&page, 7), Ok(()));
+
+    // the right bytes in the wrong place
+    assert_eq!(pages.verify(&page, 8).unwrap_err().stored, 0xd103ae196183c265);
+
+    page[100] = 1;
+    let err = pages.verify(&page, 7).unwrap_err();
+    assert_eq!(err.page_no, 7);
+    assert!(err.computed != err.stored);
+
+    let pages = PageChecksum::new(Algorithm::Xxh32, 512).unwrap();
+    let mut page = [0u8; 512];
+    pages.write(&mut page, 3);
+    assert_eq!(le32(&page, 508), 0xb97d76da);
+    assert_eq!(pages.verify(&page, 3), Ok(()));
+    assert!(pages.verify(&page, 3 + (1 << 32)).is_err());
+}
@@ -0,0 +1,258 @@
+//! Parquet split-block Bloom filters (SBBF), bit-compatible with the
+//! format spec so filters can be exchanged with other implementations.
+//!
+//! Values are hashed with xxh64 (seed 0) over their PLAIN encoding. The
+//! upper 32 bits of the hash pick a 256-bit block, and the lower 32 bits,
+//! multiplied by eight fixed salts, set one bit in each of the block's
+//! eight words.
+//!
+//! Only the bitset is handled here; the Thrift `BloomFilterHeader` in
+//! front of it belongs to the file writer.
+//!
+//! https://github.com/apache/parquet-format/blob/master/BloomFilter.md
+
+use core::prelude::*;
+use core::hash::{Hasher, Writer};
+use core::iter::repeat;
+use core::mem::transmute;
+use core::num::Int;
+
+use std::num::Float;
+use std::vec::Vec;
+
+use bytes::le32;
+use XXHasher;
+
+#[cfg(test)] use core::iter::range;
+
+const SALT: [u32; 8] = [
+    0x47b6137b, 0x44974d91, 0x8824ad5b, 0xa2b7289d,
+    0x705495c7, 0x2df1424b, 0x9efc4947, 0x5c6bfb31,
+];
+
+// one block is eight 32-bit words
+const BLOCK_BYTES: usize = 32;
+
+/// Filters never grow past this, whatever the NDV asks for.
+pub const MAX_BYTES: usize = 128 * 1024 * 1024;
+
+/// The bytes Parquet hashes for a value: the PLAIN encoding of its
+/// physical type, except that `BYTE_ARRAY` values go in without their
+/// length prefix.
+pub trait PlainEncoded {
+    fn write_plain(&self, state: &mut XXHasher);
+}
+
+macro_rules! plain_int(($t:ty, $bytes:expr) => (
+    impl PlainEncoded for $t {
+        fn write_plain(&self, state: &mut XXHasher) { #![inline]
+            let le: [u8; $bytes] = unsafe { transmute(self.to_le()) };
+            state.write(&le);
+        }
+    }
+));
+
+// INT32 and INT64, including the unsigned logical types stored in them
+plain_int!(i32, 4);
+plain_int!(u32, 4);
+plain_int!(i64, 8);
+plain_int!(u64, 8);
+
+impl PlainEncoded for f32 {
+    fn write_plain(&self, state: &mut XXHasher) { #![inline]
+        unsafe { transmute::<f32, u32>(*self) }.write_plain(state)
+    }
+}
+
+impl PlainEncoded for f64 {
+    fn write_plain(&self, state: &mut XXHasher) { #![inline]
+        unsafe { transmute::<f64, u64>(*self) }.write_plain(state)
+    }
+}
+
+/// `BYTE_ARRAY` and `FIXED_LEN_BYTE_ARRAY`.
+impl PlainEncoded for [u8] {
+    fn write_plain(&self, state: &mut XXHasher) { #![inline]
+        state.write(self)
+    }
+}
+
+/// UTF-8 strings, stored as `BYTE_ARRAY`.
+impl PlainEncoded for str {
+    fn write_plain(&self, state: &mut XXHasher) { #![inline]
+        state.write(self.as_bytes())
+    }
+}
+
+/// The legacy `INT96` timestamp, as three little-endian words.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct Int96(pub [u32; 3]);
+
+impl PlainEncoded for Int96 {
+    fn write_plain(&self, state: &mut XXHasher) {
+        for word in self.0.iter() {
+            word.write_plain(state);
+        }
+    }
+}
+
+/// The hash Parquet uses for `value`.
+pub fn hash<T: ?Sized + PlainEncoded>(value: &T) -> u64 { #![inline]
+    let mut state = XXHasher::new_with_seed(0);
+    value.write_plain(&mut state);
+    state.finish()
+}
+
+/// The filter size the spec recommends for `ndv` distinct values and a
+/// false positive probability of `fpp`, before rounding.
+pub fn optimal_num_bytes(ndv: u64, fpp: f64) -> usize {
+    let bits = -8.0 * ndv as f64 / (1.0 - fpp.powf(1.0 / 8.0)).ln();
+    (bits / 8.0) as usize
+}
+
+pub struct ParquetBloomFilter {
+    blocks: Vec<[u32; 8]>,
+}
+
+impl ParquetBloomFilter {
+    /// An empty filter. The size is rounded up to a power of two, and
+    /// clamped to between one block and `MAX_BYTES`.
+    pub fn new(num_bytes: usize) -> ParquetBloomFilter {
+        let mut size = BLOCK_BYTES;
+        while size < num_bytes && size < MAX_BYTES {
+            size *= 2;
+        }
+        ParquetBloomFilter { blocks: repeat([0u32; 8]).take(size / BLOCK_BYTES).collect() }
+    }
+
+    /// An empty filter sized for `ndv` distinct values at a false
+    /// positive probability of `fpp`.
+    pub fn with_ndv_fpp(ndv: u64, fpp: f64) -> ParquetBloomFilter {
+        ParquetBloomFilter::new(optimal_num_bytes(ndv, fpp))
+    }
+
+    /// Load a bitset as stored on disk.
+    pub fn from_bytes(bitset: &[u8]) -> Option<ParquetBloomFilter> {
+        let len = bitset.len();
+        if len < BLOCK_BYTES || len > MAX_BYTES || len & (len - 1) != 0 {
+            return None;
+        }
+
+        let blocks = bitset.chunks(BLOCK_BYTES).map(|chunk| {
+            let mut block = [0u32; 8];
+            for (i, word) in block.iter_mut().enumerate() {
+                *word = le32(chunk, 4 * i);
+            }
+            block
+        }).collect();
+        Some(ParquetBloomFilter { blocks: blocks })
+    }
+
+    /// The bitset as stored on disk: the blocks in order, each word
+    /// little-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.num_bytes());
+        for block in self.blocks.iter() {
+            for word in block.iter() {
+                let le: [u8; 4] = unsafe { transmute(word.to_le()) };
+                out.push_all(&le);
+            }
+        }
+        out
+    }
+
+    pub fn num_bytes(&self) -> usize { #![inline]
+        self.blocks.len() * BLOCK_BYTES
+    }
+
+    pub fn insert<T: ?Sized + PlainEncoded>(&mut self, value: &T) { #![inline]
+        self.insert_hash(hash(value))
+    }
+
+    /// `false` means `value` was definitely never inserted.
+    pub fn check<T: ?Sized + PlainEncoded>(&self, value: &T) -> bool { #![inline]
+        self.check_hash(hash(value))
+    }
+
+    pub fn insert_hash(&mut self, hash: u64) {
+        let i = self.block_index(hash);
+        let mask = mask(hash as u32);
+        for (word, bit) in self.blocks[i].iter_mut().zip(mask.iter()) {
+            *word |= *bit;
+        }
+    }
+
+    pub fn check_hash(&self, hash: u64) -> bool {
+        let i = self.block_index(hash);
+        let mask = mask(hash as u32);
+        self.blocks[i].iter().zip(mask.iter()).all(|(word, bit)| *word & *bit != 0)
+    }
+
+    fn block_index(&self, hash: u64) -> usize { #![inline]
+        (((hash >> 32) * self.blocks.len() as u64) >> 32) as usize
+    }
+}
+
+fn mask(key: u32) -> [u32; 8] { #![inline]
+    let mut mask = [0u32; 8];
+    for (m, salt) in mask.iter_mut().zip(SALT.iter()) {
+        *m = 1 << ((key * *salt) >> 27) as usize;
+    }
+    mask
+}
+
+#[test]
+fn test_sizing() {
+    assert_eq!(optimal_num_bytes(1000, 0.01), 1210);
+    assert_eq!(ParquetBloomFilter::with_ndv_fpp(1000, 0.01).num_bytes(), 2048);
+    assert_eq!(ParquetBloomFilter::with_ndv_fpp(1000000, 0.001).num_bytes(), 2 * 1024 * 1024);
+    assert_eq!(ParquetBloomFilter::new(0).num_bytes(), 32);
+    assert_eq!(ParquetBloomFilter::new(!0).num_bytes(), MAX_BYTES);
+}
+
+#[test]
+fn test_insert_layout() {
+    let h = hash(&42i32);
+    assert_eq!(h, 0xd756d7b62fc50bf1);
+
+    let mut filter = ParquetBloomFilter::new(2048);
+    filter.insert(&42i32);
+    assert!(filter.check(&42i32));
+    assert!(filter.check_hash(h));
+
+    // one bit in each word of block 53, the rest untouched
+    let bytes = filter.to_bytes();
+    let expected = [0x10000000, 0x1000, 0x8000000, 0x10, 0x20, 0x1000, 0x20000000, 0x40000000];
+    for i in range(0, filter.blocks.len()) {
+        for w in range(0, 8) {
+            let word = le32(bytes.as_slice(), i * BLOCK_BYTES + 4 * w);
+            assert_eq!(word, if i == 53 { expected[w] } else { 0 });
+        }
+    }
+}
+
+#[test]
+fn test_roundtrip() {
+    let mut filter = ParquetBloomFilter::with_ndv_fpp(100, 0.01);
+    for i in range(0i64, 100) {
+        filter.insert(&i);
+    }
+    filter.insert("hello");
+    filter.insert(b"hello".as_slice());
+    filter.insert(&Int96([1, 2, 3]));
+
+    let loaded = ParquetBloomFilter::from_bytes(filter.to_bytes().as_slice()).unwrap();
+    for i in range(0i64, 100) {
+        assert!(loaded.check(&i));
+    }
+    assert!(loaded.check("hello"));
+    assert!(loaded.check(&Int96([1, 2, 3])));
+    assert_eq!(hash("hello"), 0x26c7827d889f6da3);
+
+    // false positives are possible, but not for most of these
+    let misses = range(1000i64, 2000).filter(|i| !loaded.check(i)).count();
+    assert!(misses > 900);
+
+    assert!(ParquetBloomFilter::from_bytes(&[0u8; 48]).is_none());
+    assert!(ParquetBloomFilter::from_bytes(&[0u8; 16]).is_none());
+}
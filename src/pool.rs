@@ -0,0 +1,59 @@
+// A parallel map over a slice for the modules that hash many files. The
+// workers take items off a shared counter, so one big file doesn't hold
+// up a whole share of the rest, and the results come back in input order.
+
+use core::prelude::*;
+use core::cmp::min;
+use core::iter::range;
+
+use std::os::num_cpus;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread::{Thread, JoinGuard};
+use std::vec::Vec;
+
+/// `threads == 0` means one per core.
+pub fn map<T, U, F>(items: &[T], threads: usize, f: F) -> Vec<U>
+    where T: Sync, U: Send, F: Fn(&T) -> U + Sync
+{
+    let threads = min(if threads == 0 { num_cpus() } else { threads }, items.len());
+    if threads <= 1 {
+        return items.iter().map(|item| f(item)).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = channel();
+    {
+        let next = &next;
+        let f = &f;
+        let _workers: Vec<JoinGuard<()>> = range(0, threads).map(|_| {
+            let tx = tx.clone();
+            Thread::scoped(move || {
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= items.len() {
+                        break;
+                    }
+                    let _ = tx.send((i, f(&items[i])));
+                }
+            })
+        }).collect();
+    }
+    drop(tx);
+
+    let mut results: Vec<Option<U>> = range(0, items.len()).map(|_| None).collect();
+    for (i, result) in rx.iter() {
+        results[i] = Some(result);
+    }
+    results.into_iter().map(|r| r.expect("worker thread panicked")).collect()
+}
+
+#[test]
+fn test_map_keeps_order() {
+    let items: Vec<usize> = range(0, 1000).collect();
+    for &threads in [0, 1, 3, 64, 5000].iter() {
+        let squares = map(items.as_slice(), threads, |i| *i * *i);
+        assert_eq!(squares, items.iter().map(|i| *i * *i).collect::<Vec<usize>>());
+    }
+    assert!(map(&[] as &[u8], 4, |b| *b).is_empty());
+}
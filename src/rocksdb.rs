@@ -0,0 +1,602 @@
+//! Offline verification of the xxHash block checksums in RocksDB SST files.
+//!
+//! Every block of a block-based table is followed by a 5-byte trailer: the
+//! compression type and a checksum over the block contents plus that type
+//! byte. With the `kxxHash` checksum type it is the xxh32 (seed 0), with
+//! `kxxHash64` the low 32 bits of the xxh64 (seed 0).
+//!
+//! `verify` reads the footer, checks the metaindex and index blocks, walks
+//! the index to find every data block and checks each of those. It
+//! handles footer format versions 0 to 5 with the default
+//! (non-partitioned) binary search index, which must be stored
+//! uncompressed; data blocks are never decompressed. The index type comes
+//! from the properties block, and partitioned or other index types are
+//! reported as unsupported, as are CRC32c and XXH3 checksums.
+
+use core::prelude::*;
+use core::fmt;
+use core::iter::range;
+use core::num::Int;
+
+use std::error::FromError;
+use std::io::{Reader, Seek, SeekSet, SeekEnd, IoError};
+use std::io::fs::File;
+use std::path::Path;
+use std::vec::Vec;
+
+use bytes::le32;
+use xxh32;
+
+#[cfg(test)] use std::io::BufReader;
+
+const BLOCK_TRAILER_LEN: u64 = 5;
+
+const PROPERTIES_BLOCK: &'static [u8] = b"rocksdb.properties";
+const INDEX_TYPE_PROPERTY: &'static [u8] = b"rocksdb.block.based.table.index.type";
+
+// `BlockBasedTableOptions::IndexType` values that index the data blocks
+// directly; a hash search index is a binary search one plus prefixes
+const BINARY_SEARCH_INDEX: u32 = 0;
+const HASH_SEARCH_INDEX: u32 = 1;
+
+const MAGIC: u64 = 0x88e241b785f4cff7;
+const LEGACY_MAGIC: u64 = 0xdb4775248b80fb57;
+
+// checksum byte + two padded block handles + version + magic
+const FOOTER_LEN: u64 = 1 + 40 + 4 + 8;
+const LEGACY_FOOTER_LEN: u64 = 40 + 8;
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum ChecksumType {
+    NoChecksum,
+    Crc32c,
+    XxHash,
+    XxHash64,
+    Xxh3,
+    Unknown(u8),
+}
+
+impl ChecksumType {
+    fn from_u8(t: u8) -> ChecksumType {
+        match t {
+            0 => ChecksumType::NoChecksum,
+            1 => ChecksumType::Crc32c,
+            2 => ChecksumType::XxHash,
+            3 => ChecksumType::XxHash64,
+            4 => ChecksumType::Xxh3,
+            t => ChecksumType::Unknown(t),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct BlockHandle {
+    pub offset: u64,
+    /// Not counting the trailer.
+    pub size: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct Footer {
+    pub checksum: ChecksumType,
+    pub metaindex: BlockHandle,
+    pub index: BlockHandle,
+    pub format_version: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub enum Error {
+    Io(IoError),
+    BadMagic(u64),
+    UnsupportedFormat(u32),
+    UnsupportedChecksum(ChecksumType),
+    /// An index other than a plain binary search one, such as a
+    /// partitioned index (2), by its `IndexType` value.
+    UnsupportedIndex(u32),
+    /// The index block is compressed, so the data blocks can't be found.
+    CompressedIndex,
+    /// A structure that doesn't parse, at this file offset.
+    Malformed(u64),
+}
+
+impl FromError<IoError> for Error {
+    fn from_error(err: IoError) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl fmt::String for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::BadMagic(m) => write!(f, "not a block-based table (magic {:#018x})", m),
+            Error::UnsupportedFormat(v) => write!(f, "unsupported format version {}", v),
+            Error::UnsupportedChecksum(c) => write!(f, "unsupported checksum type {:?}", c),
+            Error::UnsupportedIndex(t) => write!(f, "unsupported index type {}", t),
+            Error::CompressedIndex => write!(f, "index block is compressed"),
+            Error::Malformed(offset) => write!(f, "malformed table at offset {}", offset),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum BlockKind {
+    Data,
+    Index,
+    MetaIndex,
+    Properties,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct CorruptBlock {
+    pub kind: BlockKind,
+    pub handle: BlockHandle,
+    pub stored: u32,
+    pub computed: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct Report {
+    pub footer: Footer,
+    /// Number of blocks whose checksum was checked.
+    pub checked: usize,
+    pub corrupt: Vec<CorruptBlock>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool { #![inline]
+        self.corrupt.is_empty()
+    }
+}
+
+/// The trailer checksum of `block`, which includes the compression type
+/// byte at the end.
+pub fn block_checksum(checksum: ChecksumType, block: &[u8]) -> Option<u32> {
+    match checksum {
+        ChecksumType::XxHash => Some(xxh32::oneshot(block, 0)),
+        ChecksumType::XxHash64 => Some(::oneshot(block, 0) as u32),
+        _ => None,
+    }
+}
+
+pub fn verify_file(path: &Path) -> Result<Report, Error> {
+    verify(&mut try!(File::open(path)))
+}
+
+pub fn verify<R: Reader + Seek>(file: &mut R) -> Result<Report, Error> {
+    try!(file.seek(0, SeekEnd));
+    let file_len = try!(file.tell());
+    let footer = try!(read_footer(file, file_len));
+
+    match footer.checksum {
+        ChecksumType::XxHash | ChecksumType::XxHash64 => {}
+        other => return Err(Error::UnsupportedChecksum(other)),
+    }
+
+    let mut report = Report { footer: footer, checked: 0, corrupt: Vec::new() };
+
+    let metaindex = try!(read_block(file, file_len, footer.metaindex));
+    if check(&mut report, BlockKind::MetaIndex, footer.metaindex, metaindex.as_slice()) {
+        match try!(index_type(file, file_len, &mut report, metaindex.as_slice())) {
+            Some(BINARY_SEARCH_INDEX) | Some(HASH_SEARCH_INDEX) | None => {}
+            Some(other) => return Err(Error::UnsupportedIndex(other)),
+        }
+    }
+
+    // a corrupt index can't be trusted to find the data blocks
+    let index = try!(read_block(file, file_len, footer.index));
+    if !check(&mut report, BlockKind::Index, footer.index, index.as_slice()) {
+        return Ok(report);
+    }
+    if index[footer.index.size as usize] != 0 {
+        return Err(Error::CompressedIndex);
+    }
+
+    let contents = index.slice_to(footer.index.size as usize);
+    let delta_encoded = footer.format_version >= 4;
+    for handle in try!(index_handles(contents, delta_encoded, footer.index.offset)).into_iter() {
+        let block = try!(read_block(file, file_len, handle));
+        check(&mut report, BlockKind::Data, handle, block.as_slice());
+    }
+    Ok(report)
+}
+
+fn read_footer<R: Reader + Seek>(file: &mut R, file_len: u64) -> Result<Footer, Error> {
+    if file_len < LEGACY_FOOTER_LEN {
+        return Err(Error::Malformed(0));
+    }
+    try!(file.seek((file_len - 8) as i64, SeekSet));
+    let magic = try!(file.read_le_u64());
+
+    let (start, checksum, format_version) = match magic {
+        LEGACY_MAGIC => (file_len - LEGACY_FOOTER_LEN, ChecksumType::Crc32c, 0),
+        MAGIC => {
+            if file_len < FOOTER_LEN {
+                return Err(Error::Malformed(0));
+            }
+            try!(file.seek((file_len - 12) as i64, SeekSet));
+            let format_version = try!(file.read_le_u32());
+            if format_version == 0 || format_version > 5 {
+                return Err(Error::UnsupportedFormat(format_version));
+            }
+            try!(file.seek((file_len - FOOTER_LEN) as i64, SeekSet));
+            (file_len - FOOTER_LEN + 1, ChecksumType::from_u8(try!(file.read_u8())), format_version)
+        }
+        other => return Err(Error::BadMagic(other)),
+    };
+
+    try!(file.seek(start as i64, SeekSet));
+    let handles = try!(file.read_exact(40));
+    let mut pos = 0;
+    let metaindex = try!(handle(handles.as_slice(), &mut pos).ok_or(Error::Malformed(start)));
+    let index = try!(handle(handles.as_slice(), &mut pos).ok_or(Error::Malformed(start)));
+
+    Ok(Footer { checksum: checksum, metaindex: metaindex, index: index, format_version: format_version })
+}
+
+// The block contents followed by the whole trailer.
+fn read_block<R: Reader + Seek>(file: &mut R, file_len: u64, handle: BlockHandle)
+                                -> Result<Vec<u8>, Error> {
+    // the handle comes from the file, so its sum may not fit
+    match handle.offset.checked_add(handle.size).and_then(|end| end.checked_add(BLOCK_TRAILER_LEN)) {
+        Some(end) if end <= file_len => {}
+        _ => return Err(Error::Malformed(handle.offset)),
+    }
+    try!(file.seek(handle.offset as i64, SeekSet));
+    Ok(try!(file.read_exact((handle.size + BLOCK_TRAILER_LEN) as usize)))
+}
+
+// The index type in the properties block, if the metaindex points to one
+// that is intact and uncompressed. Checks the properties block on the way.
+fn index_type<R: Reader + Seek>(file: &mut R, file_len: u64, report: &mut Report, metaindex: &[u8])
+                                -> Result<Option<u32>, Error> {
+    let meta = report.footer.metaindex;
+    if metaindex[meta.size as usize] != 0 {
+        return Ok(None);
+    }
+    let mut properties = None;
+    for (key, value) in try!(block_entries(metaindex.slice_to(meta.size as usize), meta.offset)).into_iter() {
+        if key.as_slice() == PROPERTIES_BLOCK {
+            let mut pos = 0;
+            properties = Some(try!(handle(value, &mut pos).ok_or(Error::Malformed(meta.offset))));
+        }
+    }
+    let at = match properties { Some(at) => at, None => return Ok(None) };
+
+    let block = try!(read_block(file, file_len, at));
+    if !check(report, BlockKind::Properties, at, block.as_slice()) || block[at.size as usize] != 0 {
+        return Ok(None);
+    }
+    for (key, value) in try!(block_entries(block.slice_to(at.size as usize), at.offset)).into_iter() {
+        if key.as_slice() == INDEX_TYPE_PROPERTY && value.len() == 4 {
+            return Ok(Some(le32(value, 0)));
+        }
+    }
+    Ok(None)
+}
+
+// Records a mismatch and returns whether the block was intact.
+fn check(report: &mut Report, kind: BlockKind, handle: BlockHandle, block: &[u8]) -> bool {
+    let split = handle.size as usize + 1;
+    let stored = le32(block, split);
+    let computed = block_checksum(report.footer.checksum, block.slice_to(split)).unwrap();
+    report.checked += 1;
+    if stored != computed {
+        report.corrupt.push(CorruptBlock { kind: kind, handle: handle, stored: stored, computed: computed });
+        return false;
+    }
+    true
+}
+
+// Data block handles, in order, from the entries of an index block.
+//
+// From format version 4 on, index entries carry no value length, and only
+// entries at restart points store a full handle: the others store the
+// size difference to the previous block, which they directly follow.
+fn index_handles(block: &[u8], delta_encoded: bool, block_offset: u64)
+                 -> Result<Vec<BlockHandle>, Error> {
+    let malformed = |pos: usize| Error::Malformed(block_offset + pos as u64);
+
+    let (entries, restarts) = try!(split_restarts(block, block_offset));
+    let is_restart = |pos: usize| {
+        range(0, restarts.len() / 4).any(|i| le32(restarts, 4 * i) as usize == pos)
+    };
+
+    let mut handles: Vec<BlockHandle> = Vec::new();
+    let mut pos = 0;
+    while pos < entries.len() {
+        let entry = pos;
+        macro_rules! read(() => (try!(varint(entries, &mut pos).ok_or(malformed(entry)))));
+
+        let _shared = read!();
+        let non_shared = read!();
+        let value_len = if delta_encoded { None } else { Some(read!()) };
+        pos += non_shared as usize;
+        let value_start = pos;
+
+        let handle = match handles.last() {
+            Some(prev) if delta_encoded && !is_restart(entry) => {
+                // zigzag encoded
+                let delta = read!();
+                let delta = ((delta >> 1) as i64) ^ -((delta & 1) as i64);
+                BlockHandle {
+                    offset: prev.offset + prev.size + BLOCK_TRAILER_LEN,
+                    size: (prev.size as i64 + delta) as u64,
+                }
+            }
+            _ => try!(handle(entries, &mut pos).ok_or(malformed(entry))),
+        };
+        if let Some(len) = value_len {
+            if pos != value_start + len as usize {
+                return Err(malformed(entry));
+            }
+        }
+        handles.push(handle);
+    }
+    Ok(handles)
+}
+
+// The entries of a block and its restart array.
+fn split_restarts(block: &[u8], block_offset: u64) -> Result<(&[u8], &[u8]), Error> {
+    if block.len() < 4 {
+        return Err(Error::Malformed(block_offset));
+    }
+    // the top bit flags a hash index in data blocks
+    let num_restarts = (le32(block, block.len() - 4) & 0x7FFFFFFF) as usize;
+    if num_restarts == 0 || block.len() < 4 + 4 * num_restarts {
+        return Err(Error::Malformed(block_offset + block.len() as u64 - 4));
+    }
+    let restarts_start = block.len() - 4 - 4 * num_restarts;
+    Ok((block.slice_to(restarts_start), block.slice(restarts_start, block.len() - 4)))
+}
+
+// The keys and values of a block whose entries carry value lengths, such
+// as the metaindex and properties blocks.
+fn block_entries<'a>(block: &'a [u8], block_offset: u64) -> Result<Vec<(Vec<u8>, &'a [u8])>, Error> {
+    let (entries, _) = try!(split_restarts(block, block_offset));
+    let mut found = Vec::new();
+    let mut key = Vec::new();
+    let mut pos = 0;
+    while pos < entries.len() {
+        let entry = pos;
+        let malformed = Error::Malformed(block_offset + entry as u64);
+        macro_rules! read(() => (try!(varint(entries, &mut pos).ok_or(malformed.clone()))));
+
+        let shared = read!();
+        let non_shared = read!();
+        let value_len = read!();
+        let left = (entries.len() - pos) as u64;
+        if shared > key.len() as u64 || non_shared > left || value_len > left - non_shared {
+            return Err(malformed);
+        }
+        key.truncate(shared as usize);
+        key.push_all(entries.slice(pos, pos + non_shared as usize));
+        pos += non_shared as usize;
+        found.push((key.clone(), entries.slice(pos, pos + value_len as usize)));
+        pos += value_len as usize;
+    }
+    Ok(found)
+}
+
+fn handle(buf: &[u8], pos: &mut usize) -> Option<BlockHandle> {
+    let offset = match varint(buf, pos) { Some(v) => v, None => return None };
+    let size = match varint(buf, pos) { Some(v) => v, None => return None };
+    Some(BlockHandle { offset: offset, size: size })
+}
+
+fn varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    while *pos < buf.len() && shift < 64 {
+        let b = buf[*pos];
+        *pos += 1;
+        value |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+    None
+}
+
+#[cfg(test)]
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+// Appends a block with its trailer and returns its handle.
+#[cfg(test)]
+fn put_block(file: &mut Vec<u8>, contents: &[u8], checksum: ChecksumType) -> BlockHandle {
+    let handle = BlockHandle { offset: file.len() as u64, size: contents.len() as u64 };
+    file.push_all(contents);
+    file.push(0); // uncompressed
+    let sum = block_checksum(checksum, file.slice_from(handle.offset as usize)).unwrap();
+    for i in range(0, 4) {
+        file.push((sum >> (8 * i)) as u8);
+    }
+    handle
+}
+
+// A block of `entries`, each a restart point.
+#[cfg(test)]
+fn put_entries(entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+    let mut block = Vec::new();
+    let mut restarts = Vec::new();
+    for &(key, value) in entries.iter() {
+        restarts.push(block.len() as u32);
+        put_varint(&mut block, 0);
+        put_varint(&mut block, key.len() as u64);
+        put_varint(&mut block, value.len() as u64);
+        block.push_all(key);
+        block.push_all(value);
+    }
+    if restarts.is_empty() {
+        restarts.push(0);
+    }
+    for r in restarts.iter() {
+        block.push_all(&[*r as u8, (*r >> 8) as u8, 0, 0]);
+    }
+    block.push_all(&[restarts.len() as u8, 0, 0, 0]);
+    block
+}
+
+// A table with three data blocks, restart points every `restart_interval`
+// index entries.
+#[cfg(test)]
+fn build_table(format_version: u32, checksum: ChecksumType, restart_interval: usize) -> Vec<u8> {
+    build_table_with_index_type(format_version, checksum, restart_interval, None)
+}
+
+// The same, with a properties block that records `index_type` if given.
+#[cfg(test)]
+fn build_table_with_index_type(format_version: u32, checksum: ChecksumType, restart_interval: usize,
+                               index_type: Option<u32>) -> Vec<u8> {
+    let mut file = Vec::new();
+    let blocks: Vec<BlockHandle> = range(0, 3).map(|i| {
+        let contents: Vec<u8> = range(0, 100 + 50 * i).map(|j| (i * j) as u8).collect();
+        put_block(&mut file, contents.as_slice(), checksum)
+    }).collect();
+
+    let mut index = Vec::new();
+    let mut restarts = Vec::new();
+    for (i, h) in blocks.iter().enumerate() {
+        let restart = i % restart_interval == 0;
+        if restart {
+            restarts.push(index.len() as u32);
+        }
+        let mut value = Vec::new();
+        if format_version >= 4 && !restart {
+            let delta = h.size as i64 - blocks[i - 1].size as i64;
+            put_varint(&mut value, ((delta << 1) ^ (delta >> 63)) as u64);
+        } else {
+            put_varint(&mut value, h.offset);
+            put_varint(&mut value, h.size);
+        }
+        let key = [b'k', i as u8];
+        put_varint(&mut index, 0);
+        put_varint(&mut index, key.len() as u64);
+        if format_version < 4 {
+            put_varint(&mut index, value.len() as u64);
+        }
+        index.push_all(&key);
+        index.push_all(value.as_slice());
+    }
+    for r in restarts.iter() {
+        index.push_all(&[*r as u8, (*r >> 8) as u8, 0, 0]);
+    }
+    index.push_all(&[restarts.len() as u8, 0, 0, 0]);
+
+    let mut meta = Vec::new();
+    if let Some(t) = index_type {
+        let value = [t as u8, (t >> 8) as u8, (t >> 16) as u8, (t >> 24) as u8];
+        let properties = put_entries(&[(b"rocksdb.block.based.table.index.size".as_slice(), [4u8].as_slice()),
+                                       (INDEX_TYPE_PROPERTY, value.as_slice())]);
+        let at = put_block(&mut file, properties.as_slice(), checksum);
+        put_varint(&mut meta, at.offset);
+        put_varint(&mut meta, at.size);
+    }
+    let metaindex = if meta.is_empty() {
+        put_block(&mut file, put_entries(&[]).as_slice(), checksum)
+    } else {
+        put_block(&mut file, put_entries(&[(PROPERTIES_BLOCK, meta.as_slice())]).as_slice(), checksum)
+    };
+    let index = put_block(&mut file, index.as_slice(), checksum);
+
+    let mut handles = Vec::new();
+    put_varint(&mut handles, metaindex.offset);
+    put_varint(&mut handles, metaindex.size);
+    put_varint(&mut handles, index.offset);
+    put_varint(&mut handles, index.size);
+    while handles.len() < 40 {
+        handles.push(0);
+    }
+
+    file.push(match checksum { ChecksumType::XxHash => 2, _ => 3 });
+    file.push_all(handles.as_slice());
+    for i in range(0, 4) {
+        file.push((format_version >> (8 * i)) as u8);
+    }
+    for i in range(0, 8) {
+        file.push((MAGIC >> (8 * i)) as u8);
+    }
+    file
+}
+
+#[test]
+fn test_verify_clean() {
+    for &(version, checksum) in [(2, ChecksumType::XxHash), (2, ChecksumType::XxHash64),
+                                 (5, ChecksumType::XxHash64)].iter() {
+        let file = build_table(version, checksum, 1);
+        let report = verify(&mut BufReader::new(file.as_slice())).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.checked, 5);
+        assert_eq!(report.footer.format_version, version);
+    }
+}
+
+#[test]
+fn test_delta_encoded_index() {
+    let file = build_table(4, ChecksumType::XxHash64, 16);
+    let report = verify(&mut BufReader::new(file.as_slice())).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.checked, 5);
+}
+
+#[test]
+fn test_corrupt_block() {
+    let mut file = build_table(2, ChecksumType::XxHash64, 1);
+    // inside the second data block, which starts after 100 + 5 bytes
+    file[110] ^= 0x40;
+
+    let report = verify(&mut BufReader::new(file.as_slice())).unwrap();
+    assert_eq!(report.checked, 5);
+    assert_eq!(report.corrupt.len(), 1);
+    let bad = report.corrupt[0];
+    assert_eq!(bad.kind, BlockKind::Data);
+    assert_eq!(bad.handle, BlockHandle { offset: 105, size: 150 });
+    assert!(bad.stored != bad.computed);
+}
+
+#[test]
+fn test_bad_footer() {
+    let mut file = build_table(2, ChecksumType::XxHash, 1);
+    let len = file.len();
+    file[len - 1] ^= 1;
+    match verify(&mut BufReader::new(file.as_slice())) {
+        Err(Error::BadMagic(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let file = build_table(6, ChecksumType::XxHash, 1);
+    assert_eq!(verify(&mut BufReader::new(file.as_slice())), Err(Error::UnsupportedFormat(6)));
+}
+
+#[test]
+fn test_handle_past_the_end() {
+    let file = build_table(2, ChecksumType::XxHash, 1);
+    let len = file.len() as u64;
+    let mut reader = BufReader::new(file.as_slice());
+    let huge = BlockHandle { offset: Int::max_value(), size: 10 };
+    assert_eq!(read_block(&mut reader, len, huge), Err(Error::Malformed(Int::max_value())));
+    let wraps = BlockHandle { offset: 1, size: !0 - 3 };
+    assert_eq!(read_block(&mut reader, len, wraps), Err(Error::Malformed(1)));
+}
+
+#[test]
+fn test_index_type() {
+    // a plain index is walked as before, the properties block checked too
+    for &t in [BINARY_SEARCH_INDEX, HASH_SEARCH_INDEX].iter() {
+        let file = build_table_with_index_type(5, ChecksumType::XxHash64, 1, Some(t));
+        let report = verify(&mut BufReader::new(file.as_slice())).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.checked, 6);
+    }
+
+    // a partitioned index would be walked as data blocks
+    let file = build_table_with_index_type(5, ChecksumType::XxHash64, 1, Some(2));
+    assert_eq!(verify(&mut BufReader::new(file.as_slice())), Err(Error::UnsupportedIndex(2)));
+}
@@ -0,0 +1,315 @@
+//! Per-sector xxh64 checksums of disk images, as Btrfs keeps them for
+//! data when the filesystem uses the `xxhash` checksum algorithm: every
+//! sector hashed on its own, with seed 0.
+//!
+//! `checksum_into` fills a caller-provided array and needs neither an
+//! allocator nor `std`; the reader variants stream images of any size.
+//! Whole sectors are hashed four at a time with their rounds interleaved,
+//! when the sector size is a multiple of 32 bytes, as every real one is.
+//! Stored checksum arrays are consecutive little-endian u64s, one per
+//! sector.
+
+use core::prelude::*;
+use core::cmp::{min, max};
+use core::iter::range;
+
+#[cfg(feature = "std")] use core::iter::repeat;
+#[cfg(feature = "std")] use std::io::{Reader, Writer, IoResult, IoError, InvalidInput};
+#[cfg(feature = "std")] use std::vec::Vec;
+
+#[cfg(feature = "std")] use io::read_full;
+
+use bytes::le64;
+use {PRIME1, PRIME2, PRIME3, PRIME4, rotl64};
+
+#[cfg(all(test, feature = "std"))] use std::io::BufReader;
+
+/// The Btrfs default, and the only size most kernels will mount.
+pub const SECTOR_SIZE: usize = 4096;
+
+/// A sector size in bytes, which is never zero.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct SectorSize {
+    size: usize,
+}
+
+/// `SECTOR_SIZE` as a `SectorSize`.
+pub const BTRFS: SectorSize = SectorSize { size: SECTOR_SIZE };
+
+impl SectorSize {
+    /// `None` for zero.
+    pub fn new(size: usize) -> Option<SectorSize> { #![inline]
+        if size == 0 { None } else { Some(SectorSize { size: size }) }
+    }
+
+    pub fn get(&self) -> usize { #![inline]
+        self.size
+    }
+}
+
+// how much of an image the reader variants hold at once
+#[cfg(feature = "std")]
+const BATCH_BYTES: usize = 1024 * 1024;
+
+/// The number of sectors covering `len` bytes. A short last sector counts.
+pub fn sector_count(len: u64, sector_size: SectorSize) -> u64 { #![inline]
+    (len + sector_size.size as u64 - 1) / sector_size.size as u64
+}
+
+/// Checksum consecutive sectors of `data` into `out`, stopping when either
+/// runs out. A short last sector is hashed as it is. Returns the number of
+/// checksums written.
+pub fn checksum_into(data: &[u8], sector_size: SectorSize, out: &mut [u64]) -> usize {
+    let size = sector_size.size;
+    let n = min(out.len() as u64, sector_count(data.len() as u64, sector_size)) as usize;
+    let mut i = 0;
+    if size % 32 == 0 {
+        while i + 4 <= n && (i + 4) * size <= data.len() {
+            let at = i * size;
+            let sectors = [
+                data.slice(at, at + size),
+                data.slice(at + size, at + 2 * size),
+                data.slice(at + 2 * size, at + 3 * size),
+                data.slice(at + 3 * size, at + 4 * size),
+            ];
+            checksum4(&sectors, out.slice_mut(i, i + 4));
+            i += 4;
+        }
+    }
+    while i < n {
+        out[i] = ::oneshot(data.slice(i * size, min(data.len(), (i + 1) * size)), 0);
+        i += 1;
+    }
+    n
+}
+
+fn round(acc: u64, lane: u64) -> u64 { #![inline(always)]
+    rotl64(acc + lane * PRIME2, 31) * PRIME1
+}
+
+// `oneshot(sector, 0)` for four sectors of the same length, a multiple of
+// 32. The four hashes don't depend on each other, so doing their rounds
+// side by side keeps the multipliers busy.
+fn checksum4(sectors: &[&[u8]; 4], out: &mut [u64]) {
+    let len = sectors[0].len();
+    let seed = 0u64;
+    let mut v = [[seed + PRIME1 + PRIME2, seed + PRIME2, seed, seed - PRIME1]; 4];
+    let mut at = 0;
+    while at < len {
+        for lane in range(0, 4) {
+            for s in range(0, 4) {
+                v[s][lane] = round(v[s][lane], le64(sectors[s], at + 8 * lane));
+            }
+        }
+        at += 32;
+    }
+    for s in range(0, 4) {
+        let mut h = rotl64(v[s][0], 1) + rotl64(v[s][1], 7) + rotl64(v[s][2], 12) + rotl64(v[s][3], 18);
+        for lane in range(0, 4) {
+            h ^= round(0, v[s][lane]);
+            h = h * PRIME1 + PRIME4;
+        }
+        h += len as u64;
+        h ^= h >> 33;
+        h *= PRIME2;
+        h ^= h >> 29;
+        h *= PRIME3;
+        h ^= h >> 32;
+        out[s] = h;
+    }
+}
+
+/// A run of consecutive sectors.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct SectorRange {
+    pub first: u64,
+    pub count: u64,
+}
+
+impl SectorRange {
+    /// The byte offset and length of the run in the image.
+    pub fn bytes(&self, sector_size: SectorSize) -> (u64, u64) { #![inline]
+        (self.first * sector_size.size as u64, self.count * sector_size.size as u64)
+    }
+}
+
+/// The runs of sectors whose checksums differ. Sectors that only one of
+/// the arrays covers count as mismatched.
+pub fn mismatches<'a>(computed: &'a [u64], stored: &'a [u64]) -> Mismatches<'a> { #![inline]
+    Mismatches { computed: computed, stored: stored, pos: 0 }
+}
+
+pub struct Mismatches<'a> {
+    computed: &'a [u64],
+    stored: &'a [u64],
+    pos: usize,
+}
+
+impl<'a> Mismatches<'a> {
+    fn matches(&self, i: usize) -> bool { #![inline]
+        i < self.computed.len() && i < self.stored.len() && self.computed[i] == self.stored[i]
+    }
+}
+
+impl<'a> Iterator for Mismatches<'a> {
+    type Item = SectorRange;
+
+    fn next(&mut self) -> Option<SectorRange> {
+        let len = max(self.computed.len(), self.stored.len());
+        while self.pos < len && self.matches(self.pos) {
+            self.pos += 1;
+        }
+        if self.pos == len {
+            return None;
+        }
+        let first = self.pos;
+        while self.pos < len && !self.matches(self.pos) {
+            self.pos += 1;
+        }
+        Some(SectorRange { first: first as u64, count: (self.pos - first) as u64 })
+    }
+}
+
+#[cfg(feature = "std")]
+pub fn checksum_sectors(data: &[u8], sector_size: SectorSize) -> Vec<u64> {
+    let mut sums: Vec<u64> = repeat(0).take(sector_count(data.len() as u64, sector_size) as usize).collect();
+    checksum_into(data, sector_size, sums.as_mut_slice());
+    sums
+}
+
+/// Checksum every sector of an image, reading it in large batches.
+#[cfg(feature = "std")]
+pub fn checksum_reader<R: Reader>(reader: &mut R, sector_size: SectorSize) -> IoResult<Vec<u64>> {
+    let batch = max(1, BATCH_BYTES / sector_size.size) * sector_size.size;
+    let mut buf: Vec<u8> = repeat(0).take(batch).collect();
+    let mut sums = Vec::new();
+    loop {
+        let n = try!(read_full(reader, buf.as_mut_slice()));
+        let start = sums.len();
+        sums.extend(repeat(0).take(sector_count(n as u64, sector_size) as usize));
+        checksum_into(buf.slice_to(n), sector_size, sums.slice_from_mut(start));
+        if n < batch {
+            return Ok(sums);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub fn verify(data: &[u8], sector_size: SectorSize, stored: &[u64]) -> Vec<SectorRange> {
+    let computed = checksum_sectors(data, sector_size);
+    mismatches(computed.as_slice(), stored).collect()
+}
+
+#[cfg(feature = "std")]
+pub fn verify_reader<R: Reader>(reader: &mut R, sector_size: SectorSize, stored: &[u64])
+                                -> IoResult<Vec<SectorRange>> {
+    let computed = try!(checksum_reader(reader, sector_size));
+    Ok(mismatches(computed.as_slice(), stored).collect())
+}
+
+#[cfg(feature = "std")]
+pub fn write_checksums<W: Writer>(writer: &mut W, sums: &[u64]) -> IoResult<()> {
+    for sum in sums.iter() {
+        try!(writer.write_le_u64(*sum));
+    }
+    Ok(())
+}
+
+/// Read a whole stored checksum array.
+#[cfg(feature = "std")]
+pub fn read_checksums<R: Reader>(reader: &mut R) -> IoResult<Vec<u64>> {
+    let bytes = try!(reader.read_to_end());
+    if bytes.len() % 8 != 0 {
+        return Err(IoError {
+            kind: InvalidInput,
+            desc: "checksum array is not a whole number of u64s",
+            detail: None,
+        });
+    }
+    Ok(range(0, bytes.len() / 8).map(|i| le64(bytes.as_slice(), 8 * i)).collect())
+}
+
+#[test]
+fn test_checksum_into() {
+    let zeros = [0u8; 2 * SECTOR_SIZE + 512];
+    let mut sums = [0u64; 4];
+    assert_eq!(checksum_into(&zeros, BTRFS, &mut sums), 3);
+    assert_eq!(sums, [0xac869b6f32d8bbdb, 0xac869b6f32d8bbdb, 0x8bbf7f68e8c3b87c, 0]);
+
+    // a short array takes what fits
+    let mut sums = [0u64; 1];
+    assert_eq!(checksum_into(&zeros, SectorSize::new(512).unwrap(), &mut sums), 1);
+    assert_eq!(sums[0], 0x8bbf7f68e8c3b87c);
+
+    // four at a time and one by one agree, with and without a short tail
+    let mut image = [0u8; 9 * 512 + 100];
+    for (i, b) in image.iter_mut().enumerate() {
+        *b = (i * 7 / 5) as u8;
+    }
+    for &size in [32, 64, 100, 512].iter() {
+        let size = SectorSize::new(size).unwrap();
+        let mut sums = [0u64; 200];
+        let n = checksum_into(&image, size, &mut sums);
+        assert_eq!(n as u64, sector_count(image.len() as u64, size));
+        for (i, sector) in image.chunks(size.get()).enumerate() {
+            assert_eq!(sums[i], ::oneshot(sector, 0));
+        }
+        let mut few = [0u64; 6];
+        assert_eq!(checksum_into(&image, size, &mut few), 6);
+        assert_eq!(few.as_slice(), sums.slice_to(6));
+    }
+
+    assert_eq!(sector_count(0, BTRFS), 0);
+    assert_eq!(sector_count(zeros.len() as u64, BTRFS), 3);
+    assert_eq!(BTRFS.get(), SECTOR_SIZE);
+    assert!(SectorSize::new(0).is_none());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_reader_matches_slices() {
+    // several batches and a short last sector
+    let image: Vec<u8> = range(0, 2 * BATCH_BYTES + 3000).map(|i| (i * 7 / 5) as u8).collect();
+    for &size in [512, SECTOR_SIZE, 64 * 1024].iter() {
+        let size = SectorSize::new(size).unwrap();
+        let sums = checksum_reader(&mut BufReader::new(image.as_slice()), size).unwrap();
+        assert_eq!(sums, checksum_sectors(image.as_slice(), size));
+    }
+    assert!(checksum_reader(&mut BufReader::new(&[]), BTRFS).unwrap().is_empty());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_verify() {
+    let small = SectorSize::new(512).unwrap();
+    let mut image: Vec<u8> = range(0, 10 * 512).map(|i| i as u8).collect();
+    let stored = checksum_sectors(image.as_slice(), small);
+    assert_eq!(stored.len(), 10);
+    assert!(verify(image.as_slice(), small, stored.as_slice()).is_empty());
+
+    // sectors 2 and 3 merge into one run, 7 stands alone
+    image[2 * 512 + 1] ^= 1;
+    image[3 * 512 + 511] ^= 1;
+    image[7 * 512] ^= 1;
+    let bad = verify_reader(&mut BufReader::new(image.as_slice()), small, stored.as_slice()).unwrap();
+    assert_eq!(bad, vec![SectorRange { first: 2, count: 2 }, SectorRange { first: 7, count: 1 }]);
+    assert_eq!(bad[0].bytes(small), (1024, 1024));
+
+    // a truncated image leaves its missing sectors unmatched
+    let bad = verify(image.slice_to(8 * 512), small, stored.as_slice());
+    assert_eq!(bad.last(), Some(&SectorRange { first: 7, count: 3 }));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_stored_array() {
+    let sums = [0xac869b6f32d8bbdb, 1, !0];
+    let mut out = Vec::new();
+    write_checksums(&mut out, &sums).unwrap();
+    assert_eq!(out.len(), 24);
+    assert_eq!(out.slice_to(8), [0xdb, 0xbb, 0xd8, 0x32, 0x6f, 0x9b, 0x86, 0xac].as_slice());
+    let read = read_checksums(&mut BufReader::new(out.as_slice())).unwrap();
+    assert_eq!(read.as_slice(), sums.as_slice());
+
+    assert!(read_checksums(&mut BufReader::new(out.slice_to(20))).is_err());
+}
@@ -0,0 +1,285 @@
+//! One digest for a whole directory tree, for build caches and the like:
+//! it changes whenever a file is added, removed, renamed or edited.
+//!
+//! Regular files and symlinks are the entries; directories only show up
+//! in their paths, so an empty directory doesn't count. Paths are the raw
+//! bytes of the names, UTF-8 or not, relative to the root with `/` as the
+//! separator on every platform, and entries are visited in their byte
+//! order.
+//!
+//! Each entry has a digest of its own: the xxh64 (seed 0) of the file's
+//! contents or of the link's target. The tree digest feeds, for each
+//! entry in order, the path length and path, the entry type, the mode
+//! bits if asked for, and the entry digest into one `XXHasher` (seed 0).
+
+use core::prelude::*;
+use core::iter::range_inclusive;
+use core::hash::{Hasher, Writer};
+use core::mem::transmute;
+use core::num::Int;
+
+use std::io::{IoResult, FileType};
+use std::io::fs::{stat, readlink};
+use std::path::{Path, GenericPath};
+use std::string::String;
+use std::vec::Vec;
+
+use io::hash_file;
+use pool;
+use walk::walk;
+use XXHasher;
+
+#[cfg(test)] use core::default::Default;
+#[cfg(test)] use core::str::from_utf8;
+#[cfg(test)] use std::io::{TempDir, FilePermission};
+#[cfg(test)] use std::io::fs::{mkdir, chmod, symlink, unlink};
+#[cfg(test)] use walk::write_file;
+
+#[derive(Clone, PartialEq, Eq, Show, Default)]
+pub struct TreeOptions {
+    /// Globs an entry's path has to match one of, if any are given.
+    pub include: Vec<String>,
+    /// Globs for entries, or whole directories, to leave out.
+    pub exclude: Vec<String>,
+    /// Hash permission bits too.
+    pub mode: bool,
+    /// Hash links to files as the files they point to. Links to
+    /// directories are always hashed as links, which rules out cycles.
+    pub follow_symlinks: bool,
+    /// Threads reading files; 0 for one per core.
+    pub threads: usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum EntryKind {
+    File,
+    Symlink,
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct TreeEntry {
+    /// Relative to the root, `/`-separated, as the name bytes on disk.
+    pub path: Vec<u8>,
+    pub kind: EntryKind,
+    /// Permission bits, if `TreeOptions::mode` is set.
+    pub mode: Option<u32>,
+    pub digest: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Show)]
+pub struct TreeDigest {
+    pub digest: u64,
+    pub entries: Vec<TreeEntry>,
+}
+
+/// Whether `path` matches `pattern`, where `?` is any one character but
+/// `/`, `*` any run of them and `**` any run at all, slashes included.
+/// `**/` also matches nothing, so `**/*.rs` takes `main.rs` as well.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    glob(pattern.as_bytes(), path.as_bytes())
+}
+
+fn glob(p: &[u8], s: &[u8]) -> bool {
+    if p.is_empty() {
+        return s.is_empty();
+    }
+    if p.starts_with(b"**") {
+        let rest = p.slice_from(2);
+        if rest.starts_with(b"/") && glob(rest.slice_from(1), s) {
+            return true;
+        }
+        return range_inclusive(0, s.len()).any(|i| glob(rest, s.slice_from(i)));
+    }
+    match p[0] {
+        b'*' => {
+            let rest = p.slice_from(1);
+            let mut i = 0;
+            loop {
+                if glob(rest, s.slice_from(i)) {
+                    return true;
+                }
+                if i == s.len() || s[i] == b'/' {
+                    return false;
+                }
+                i += 1;
+            }
+        }
+        b'?' => !s.is_empty() && s[0] != b'/' && glob(p.slice_from(1), s.slice_from(1)),
+        c => !s.is_empty() && s[0] == c && glob(p.slice_from(1), s.slice_from(1)),
+    }
+}
+
+// What the walk finds, before any file is read.
+struct Found {
+    path: Vec<u8>,
+    full: Path,
+    kind: EntryKind,
+    mode: Option<u32>,
+}
+
+pub fn hash_tree(root: &Path, options: &TreeOptions) -> IoResult<TreeDigest> {
+    let found = try!(find(root, options));
+
+    let digests = pool::map(found.as_slice(), options.threads, |entry| {
+        match entry.kind {
+            EntryKind::File => hash_file(&entry.full, 0),
+            EntryKind::Symlink => readlink(&entry.full).map(|target| ::oneshot(target.as_vec(), 0)),
+        }
+    });
+
+    let mut state = XXHasher::new_with_seed(0);
+    let mut entries = Vec::with_capacity(found.len());
+    for (entry, digest) in found.into_iter().zip(digests.into_iter()) {
+        let digest = try!(digest);
+        let len: [u8; 8] = unsafe { transmute((entry.path.len() as u64).to_le()) };
+        state.write(&len);
+        state.write(entry.path.as_slice());
+        state.write(&[match entry.kind { EntryKind::File => 0, EntryKind::Symlink => 1 }]);
+        if let Some(mode) = entry.mode {
+            let mode: [u8; 4] = unsafe { transmute(mode.to_le()) };
+            state.write(&mode);
+        }
+        let le: [u8; 8] = unsafe { transmute(digest.to_le()) };
+        state.write(&le);
+
+        entries.push(TreeEntry { path: entry.path, kind: entry.kind, mode: entry.mode, digest: digest });
+    }
+    Ok(TreeDigest { digest: state.finish(), entries: entries })
+}
+
+// The entries to hash, in path order.
+fn find(root: &Path, options: &TreeOptions) -> IoResult<Vec<Found>> {
+    let matches = |globs: &Vec<String>, path: &[u8]| globs.iter().any(|g| glob(g.as_bytes(), path));
+    let walked = try!(walk(root, |path| matches(&options.exclude, path)));
+
+    let mut found = Vec::with_capacity(walked.len());
+    for entry in walked.into_iter() {
+        let mut meta = entry.stat;
+        let file_type = meta.kind;
+        let kind = match file_type {
+            FileType::RegularFile => EntryKind::File,
+            FileType::Symlink if options.follow_symlinks => match stat(&entry.full) {
+                // dangling links and links to directories stay links
+                Ok(target) => if target.kind == FileType::RegularFile {
+                    meta = target;
+                    EntryKind::File
+                } else {
+                    EntryKind::Symlink
+                },
+                Err(_) => EntryKind::Symlink,
+            },
+            FileType::Symlink => EntryKind::Symlink,
+            // sockets, fifos and devices have no contents to speak of
+            _ => continue,
+        };
+        if !options.include.is_empty() && !matches(&options.include, entry.path.as_slice()) {
+            continue;
+        }
+        let mode = if options.mode { Some(meta.perm.bits() as u32 & 0o7777) } else { None };
+        found.push(Found { path: entry.path, full: entry.full, kind: kind, mode: mode });
+    }
+    Ok(found)
+}
+
+#[test]
+fn test_glob() {
+    assert!(glob_match("*.rs", "main.rs"));
+    assert!(!glob_match("*.rs", "src/main.rs"));
+    assert!(glob_match("**/*.rs", "main.rs"));
+    assert!(glob_match("**/*.rs", "src/bin/main.rs"));
+    assert!(glob_match("src/**", "src/a/b"));
+    assert!(glob_match("target", "target"));
+    assert!(!glob_match("target", "target/debug"));
+    assert!(glob_match("?.txt", "a.txt"));
+    assert!(!glob_match("?.txt", "ab.txt"));
+    assert!(glob_match("a/*/c", "a/b/c"));
+    assert!(!glob_match("a/*/c", "a/b/b/c"));
+}
+
+#[test]
+fn test_hash_tree() {
+    let dir = TempDir::new("xxhash-tree").unwrap();
+    let root = dir.path();
+    mkdir(&root.join("src"), FilePermission::from_bits_truncate(0o755)).unwrap();
+    mkdir(&root.join("target"), FilePermission::from_bits_truncate(0o755)).unwrap();
+    write_file(&root.join("src/main.rs"), b"fn main() {}");
+    write_file(&root.join("src/lib.rs"), b"");
+    write_file(&root.join("Cargo.toml"), b"[package]");
+    write_file(&root.join("target/out"), b"build output");
+
+    let mut options = TreeOptions { exclude: vec![String::from_str("target")], ..Default::default() };
+    let first = hash_tree(root, &options).unwrap();
+    let paths: Vec<&str> = first.entries.iter().map(|e| from_utf8(e.path.as_slice()).unwrap()).collect();
+    assert_eq!(paths, vec!["Cargo.toml", "src/lib.rs", "src/main.rs"]);
+    assert_eq!(first.entries[2].digest, ::oneshot(b"fn main() {}", 0));
+
+    // the same with one thread, and untouched by excluded changes
+    options.threads = 1;
+    write_file(&root.join("target/out"), b"other output");
+    assert_eq!(hash_tree(root, &options).unwrap(), first);
+
+    // an edit
+    write_file(&root.join("src/lib.rs"), b"//");
+    let edited = hash_tree(root, &options).unwrap();
+    assert!(edited.digest != first.digest);
+
+    // a rename with the same contents
+    write_file(&root.join("src/lib.rs"), b"");
+    write_file(&root.join("src/lib2.rs"), b"");
+    unlink(&root.join("src/lib.rs")).unwrap();
+    assert!(hash_tree(root, &options).unwrap().digest != first.digest);
+    unlink(&root.join("src/lib2.rs")).unwrap();
+    write_file(&root.join("src/lib.rs"), b"");
+    assert_eq!(hash_tree(root, &options).unwrap().digest, first.digest);
+
+    options.include = vec![String::from_str("**/*.rs")];
+    assert_eq!(hash_tree(root, &options).unwrap().entries.len(), 2);
+}
+
+#[test]
+fn test_mode_and_symlinks() {
+    let dir = TempDir::new("xxhash-tree").unwrap();
+    let root = dir.path();
+    write_file(&root.join("run.sh"), b"#!/bin/sh");
+    symlink(&Path::new("run.sh"), &root.join("link")).unwrap();
+
+    let mut options: TreeOptions = Default::default();
+    let plain = hash_tree(root, &options).unwrap();
+    assert_eq!(plain.entries[0].kind, EntryKind::Symlink);
+    assert_eq!(plain.entries[0].digest, ::oneshot(b"run.sh", 0));
+
+    options.follow_symlinks = true;
+    let followed = hash_tree(root, &options).unwrap();
+    assert_eq!(followed.entries[0].kind, EntryKind::File);
+    assert_eq!(followed.entries[0].digest, followed.entries[1].digest);
+
+    options.follow_symlinks = false;
+    options.mode = true;
+    chmod(&root.join("run.sh"), FilePermission::from_bits_truncate(0o644)).unwrap();
+    let before = hash_tree(root, &options).unwrap();
+    assert_eq!(before.entries[1].mode, Some(0o644));
+    chmod(&root.join("run.sh"), FilePermission::from_bits_truncate(0o755)).unwrap();
+    let after = hash_tree(root, &options).unwrap();
+    assert!(after.digest != before.digest);
+
+    options.mode = false;
+    assert_eq!(hash_tree(root, &options).unwrap().digest, plain.digest);
+}
+
+#[test]
+fn test_non_utf8_names() {
+    let dir = TempDir::new("xxhash-tree").unwrap();
+    let root = dir.path();
+    // both are "a\u{fffd}" once made UTF-8
+    write_file(&root.join(b"a\xfe".as_slice()), b"one");
+    write_file(&root.join(b"a\xff".as_slice()), b"two");
+
+    let options: TreeOptions = Default::default();
+    let before = hash_tree(root, &options).unwrap();
+    assert_eq!(before.entries.len(), 2);
+    assert_eq!(before.entries[1].path.as_slice(), b"a\xff".as_slice());
+
+    // an edit to either one shows
+    write_file(&root.join(b"a\xfe".as_slice()), b"changed");
+    assert!(hash_tree(root, &options).unwrap().digest != before.digest);
+}
@@ -0,0 +1,82 @@
+// Walking a directory tree for the modules that hash one. Paths are kept
+// as the bytes the OS gave us, relative to the root and `/`-separated, so
+// two names that only differ in bytes that aren't UTF-8 stay two entries,
+// and the order is the byte order of those paths on every platform.
+
+use core::prelude::*;
+
+use std::collections::BTreeMap;
+use std::io::{IoResult, FileStat, FileType};
+use std::io::fs::{readdir, lstat};
+use std::path::Path;
+use std::vec::Vec;
+
+#[cfg(test)] use std::io::{TempDir, FilePermission};
+#[cfg(test)] use std::io::fs::{File, mkdir};
+
+pub struct Entry {
+    /// Relative to the root, `/`-separated.
+    pub path: Vec<u8>,
+    pub full: Path,
+    /// From `lstat`, so a symlink is a symlink.
+    pub stat: FileStat,
+}
+
+/// Everything under `root` but the directories themselves, by path.
+/// `skip` sees every path, directories included, and an entry it returns
+/// true for is left out, a directory with all of its contents.
+pub fn walk<F: FnMut(&[u8]) -> bool>(root: &Path, mut skip: F) -> IoResult<Vec<Entry>> {
+    let mut found = BTreeMap::new();
+    try!(walk_dir(root, &[], &mut skip, &mut found));
+    Ok(found.into_iter().map(|(path, (full, stat))| Entry { path: path, full: full, stat: stat }).collect())
+}
+
+fn walk_dir<F: FnMut(&[u8]) -> bool>(dir: &Path, prefix: &[u8], skip: &mut F,
+                                     found: &mut BTreeMap<Vec<u8>, (Path, FileStat)>)
+                                     -> IoResult<()> {
+    for full in try!(readdir(dir)).into_iter() {
+        let mut path = Vec::with_capacity(prefix.len() + 16);
+        path.push_all(prefix);
+        match full.filename() {
+            Some(name) => path.push_all(name),
+            None => continue,
+        }
+        if (*skip)(path.as_slice()) {
+            continue;
+        }
+        let stat = try!(lstat(&full));
+        if stat.kind == FileType::Directory {
+            path.push(b'/');
+            try!(walk_dir(&full, path.as_slice(), skip, found));
+        } else {
+            found.insert(path, (full, stat));
+        }
+    }
+    Ok(())
+}
+
+/// Test fixture: `path` with exactly `contents` in it.
+#[cfg(test)]
+pub fn write_file(path: &Path, contents: &[u8]) {
+    ::std::io::Writer::write(&mut File::create(path).unwrap(), contents).unwrap();
+}
+
+#[test]
+fn test_walk() {
+    let dir = TempDir::new("xxhash-walk").unwrap();
+    let root = dir.path();
+    mkdir(&root.join("b"), FilePermission::from_bits_truncate(0o755)).unwrap();
+    mkdir(&root.join("skipped"), FilePermission::from_bits_truncate(0o755)).unwrap();
+    write_file(&root.join("b/c"), b"");
+    write_file(&root.join("skipped/d"), b"");
+    write_file(&root.join("a"), b"");
+    // not UTF-8, and the same once made so
+    write_file(&root.join(b"x\xfe".as_slice()), b"");
+    write_file(&root.join(b"x\xff".as_slice()), b"");
+
+    let entries = walk(root, |path| path == b"skipped".as_slice()).unwrap();
+    let paths: Vec<&[u8]> = entries.iter().map(|e| e.path.as_slice()).collect();
+    assert_eq!(paths, vec![b"a".as_slice(), b"b/c".as_slice(), b"x\xfe".as_slice(), b"x\xff".as_slice()]);
+    assert_eq!(entries[1].full, root.join("b/c"));
+    assert_eq!(entries[0].stat.kind, FileType::RegularFile);
+}
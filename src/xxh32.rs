@@ -1,10 +1,13 @@
-use std::mem::{uninitialized,transmute};
-use std::num::Int;
-use std::raw::{Repr};
-use std::ptr::{copy_memory};
-use std::hash::{Hash, Hasher, Writer};
-use std::default::Default;
-
+use core::prelude::*;
+use core::mem::{uninitialized,transmute};
+use core::num::Int;
+use core::raw::{Repr};
+use core::ptr::{copy_memory};
+use core::hash::{Hash, Hasher, Writer};
+use core::default::Default;
+
+#[cfg(test)] use core::iter::range;
+#[cfg(test)] use std::vec::Vec;
 #[cfg(test)] use test::Bencher;
 
 fn rotl32(x: u32, b: usize) -> u32 { #![inline(always)]
@@ -48,6 +51,12 @@ impl XXHasher {
     pub fn new() -> XXHasher { #![inline]
         XXHasher::new_with_seed(0)
     }
+
+    /// Seeded from the thread-local RNG.
+    #[cfg(feature = "std")]
+    pub fn new_random() -> XXHasher { #![inline]
+        XXHasher::new_with_seed(::std::rand::random())
+    }
 }
 
 impl Writer for XXHasher {
@@ -261,6 +270,12 @@ fn test_chunks() {
     })
 }
 
+#[test]
+fn test_literal_macro() {
+    assert_eq!(xxh32!("Message::Ping"), 0xCA51E9E3);
+    assert_eq!(xxh32!("Message::Ping"), oneshot(b"Message::Ping", 0));
+}
+
 #[bench]
 fn bench_64k_oneshot(b: &mut Bencher) {
     bench_base(b, |v| { oneshot(v, 0) })
@@ -0,0 +1,392 @@
+//! Zstandard frame content checksums.
+//!
+//! A zstd frame may end with the low 32 bits of the xxh64 (seed 0) of the
+//! decompressed content, stored little-endian after the last block. This
+//! module computes that checksum, walks a frame's header and block headers
+//! to find the stored one, and checks it against decompressed bytes.
+//! Nothing is decompressed here.
+//!
+//! https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md
+
+use core::prelude::*;
+use core::fmt;
+use core::hash::{Hasher, Writer};
+
+#[cfg(feature = "std")] use std::io::{Reader, IoResult};
+
+use bytes::{le16, le32, le64};
+use XXHasher;
+
+use self::ErrorKind::*;
+
+pub const MAGIC: u32 = 0xFD2FB528;
+
+// the format caps every block at 128 KiB
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+/// The zstd checksum of `content`.
+pub fn checksum(content: &[u8]) -> u32 { #![inline]
+    ::oneshot(content, 0) as u32
+}
+
+/// Computes the zstd checksum of content fed in pieces.
+#[derive(Copy, Clone)]
+pub struct ContentChecksum {
+    state: XXHasher,
+}
+
+impl ContentChecksum {
+    pub fn new() -> ContentChecksum { #![inline]
+        ContentChecksum { state: XXHasher::new_with_seed(0) }
+    }
+
+    pub fn update(&mut self, content: &[u8]) { #![inline]
+        self.state.write(content)
+    }
+
+    pub fn finish(&self) -> u32 { #![inline]
+        self.state.finish() as u32
+    }
+}
+
+/// The zstd checksum of everything `reader` produces.
+#[cfg(feature = "std")]
+pub fn checksum_reader<R: Reader>(reader: &mut R) -> IoResult<u32> {
+    ::io::hash_reader(reader, 0).map(|h| h as u32)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum ErrorKind {
+    Truncated,
+    BadMagic(u32),
+    ReservedBits,
+    ReservedBlockType,
+    BlockTooLarge(usize),
+    /// The frame was written without a content checksum.
+    NoChecksum,
+    ContentSize { stored: u64, actual: u64 },
+    ContentChecksum { stored: u32, computed: u32 },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// Offset from the start of the frame.
+    pub offset: usize,
+}
+
+impl fmt::String for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(match self.kind {
+            Truncated => write!(f, "frame truncated"),
+            BadMagic(m) => write!(f, "not a zstd frame (magic {:#010x})", m),
+            ReservedBits => write!(f, "reserved bits set"),
+            ReservedBlockType => write!(f, "reserved block type"),
+            BlockTooLarge(n) => write!(f, "block of {} bytes exceeds the maximum", n),
+            NoChecksum => write!(f, "frame has no content checksum"),
+            ContentSize { stored, actual } =>
+                write!(f, "content size {}, frame says {}", actual, stored),
+            ContentChecksum { stored, computed } =>
+                write!(f, "content checksum {:#010x}, expected {:#010x}", stored, computed),
+        });
+        write!(f, " at offset {}", self.offset)
+    }
+}
+
+fn error<T>(kind: ErrorKind, offset: usize) -> Result<T, Error> { #![inline]
+    Err(Error { kind: kind, offset: offset })
+}
+
+fn need(frame: &[u8], at: usize, len: usize) -> Result<(), Error> { #![inline]
+    if frame.len() < at + len {
+        return error(Truncated, at);
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct FrameHeader {
+    pub single_segment: bool,
+    pub content_checksum: bool,
+    /// Absent in single-segment frames.
+    pub window_size: Option<u64>,
+    pub dict_id: Option<u32>,
+    pub content_size: Option<u64>,
+    /// Including the magic number.
+    pub header_len: usize,
+}
+
+pub fn parse_header(frame: &[u8]) -> Result<FrameHeader, Error> {
+    try!(need(frame, 0, 5));
+    let magic = le32(frame, 0);
+    if magic != MAGIC {
+        return error(BadMagic(magic), 0);
+    }
+
+    let fhd = frame[4];
+    if fhd & 0x08 != 0 {
+        return error(ReservedBits, 4);
+    }
+    let single_segment = fhd & 0x20 != 0;
+    let mut pos = 5;
+
+    let window_size = if single_segment {
+        None
+    } else {
+        try!(need(frame, pos, 1));
+        let wd = frame[pos];
+        pos += 1;
+        let base = 1u64 << (10 + (wd >> 3) as usize);
+        Some(base + (base / 8) * (wd & 0x07) as u64)
+    };
+
+    let dict_len = [0, 1, 2, 4][(fhd & 0x03) as usize];
+    try!(need(frame, pos, dict_len));
+    let dict_id = match dict_len {
+        0 => None,
+        1 => Some(frame[pos] as u32),
+        2 => Some(le16(frame, pos) as u32),
+        _ => Some(le32(frame, pos)),
+    };
+    pos += dict_len;
+
+    let fcs_len = fcs_len(fhd);
+    try!(need(frame, pos, fcs_len));
+    let content_size = match fcs_len {
+        0 => None,
+        1 => Some(frame[pos] as u64),
+        2 => Some(le16(frame, pos) as u64 + 256),
+        4 => Some(le32(frame, pos) as u64),
+        _ => Some(le64(frame, pos)),
+    };
+    pos += fcs_len;
+
+    Ok(FrameHeader {
+        single_segment: single_segment,
+        content_checksum: fhd & 0x04 != 0,
+        window_size: window_size,
+        dict_id: dict_id,
+        content_size: content_size,
+        header_len: pos,
+    })
+}
+
+// size of the Frame_Content_Size field
+fn fcs_len(fhd: u8) -> usize {
+    match fhd >> 6 {
+        0 if fhd & 0x20 != 0 => 1,
+        0 => 0,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub enum BlockType {
+    Raw,
+    Rle,
+    Compressed,
+}
+
+/// A block header. RLE blocks store one byte, the others `size` bytes.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct Block {
+    /// Offset of the block header.
+    pub offset: usize,
+    pub block_type: BlockType,
+    pub size: usize,
+    pub last: bool,
+}
+
+impl Block {
+    /// Offset of the next block, or of whatever follows the last one.
+    pub fn end(&self) -> usize {
+        let stored = if self.block_type == BlockType::Rle { 1 } else { self.size };
+        self.offset + 3 + stored
+    }
+}
+
+/// Iterator over the block headers of a frame. Stops after the last
+/// block, or after the first error.
+pub struct Blocks<'a> {
+    frame: &'a [u8],
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> Blocks<'a> {
+    pub fn new(frame: &'a [u8], header: &FrameHeader) -> Blocks<'a> {
+        Blocks { frame: frame, pos: header.header_len, done: false }
+    }
+
+    fn next_block(&mut self) -> Result<Block, Error> {
+        let offset = self.pos;
+        try!(need(self.frame, offset, 3));
+        let raw = le16(self.frame, offset) as u32 | (self.frame[offset + 2] as u32) << 16;
+
+        let block_type = match (raw >> 1) & 0x03 {
+            0 => BlockType::Raw,
+            1 => BlockType::Rle,
+            2 => BlockType::Compressed,
+            _ => return error(ReservedBlockType, offset),
+        };
+        let size = (raw >> 3) as usize;
+        if size > MAX_BLOCK_SIZE {
+            return error(BlockTooLarge(size), offset);
+        }
+
+        let block = Block { offset: offset, block_type: block_type, size: size, last: raw & 1 != 0 };
+        if self.frame.len() < block.end() {
+            return error(Truncated, offset + 3);
+        }
+        self.pos = block.end();
+        Ok(block)
+    }
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = Result<Block, Error>;
+
+    fn next(&mut self) -> Option<Result<Block, Error>> {
+        if self.done {
+            return None;
+        }
+        let block = self.next_block();
+        self.done = match block {
+            Ok(ref b) => b.last,
+            Err(_) => true,
+        };
+        Some(block)
+    }
+}
+
+/// What `parse_frame` found.
+#[derive(Copy, Clone, PartialEq, Eq, Show)]
+pub struct FrameInfo {
+    pub header: FrameHeader,
+    pub blocks: usize,
+    /// The stored checksum and its offset, if the frame has one.
+    pub checksum: Option<(u32, usize)>,
+    /// Length of the whole frame; the next frame, if any, starts here.
+    pub frame_len: usize,
+}
+
+/// Walk the frame at the start of `frame` to its end.
+pub fn parse_frame(frame: &[u8]) -> Result<FrameInfo, Error> {
+    let header = try!(parse_header(frame));
+    let mut count = 0;
+    let mut end = header.header_len;
+    for block in Blocks::new(frame, &header) {
+        end = try!(block).end();
+        count += 1;
+    }
+
+    let checksum = if header.content_checksum {
+        try!(need(frame, end, 4));
+        end += 4;
+        Some((le32(frame, end - 4), end - 4))
+    } else {
+        None
+    };
+
+    Ok(FrameInfo { header: header, blocks: count, checksum: checksum, frame_len: end })
+}
+
+/// Check `decompressed` against the frame's stored checksum, and against
+/// its content size if it records one.
+pub fn verify_frame(frame: &[u8], decompressed: &[u8]) -> Result<FrameInfo, Error> {
+    let info = try!(parse_frame(frame));
+
+    if let Some(stored) = info.header.content_size {
+        if stored != decompressed.len() as u64 {
+            let offset = info.header.header_len - fcs_len(frame[4]);
+            return error(ContentSize { stored: stored, actual: decompressed.len() as u64 }, offset);
+        }
+    }
+
+    let (stored, offset) = match info.checksum {
+        Some(checksum) => checksum,
+        None => return error(NoChecksum, 4),
+    };
+    let computed = checksum(decompressed);
+    if stored != computed {
+        return error(ContentChecksum { stored: stored, computed: computed }, offset);
+    }
+    Ok(info)
+}
+
+// `zstd --check`, a single compressed block
+#[cfg(test)]
+static COMPRESSED: [u8; 31] = [
+    0x28, 0xb5, 0x2f, 0xfd, 0x24, 0x30, 0x95, 0x00, 0x00, 0x60, 0x68, 0x65,
+    0x6c, 0x6c, 0x6f, 0x20, 0x78, 0x78, 0x68, 0x61, 0x73, 0x68, 0x01, 0x00,
+    0x43, 0x96, 0x22, 0x7c, 0xeb, 0x45, 0xf5,
+];
+
+#[cfg(test)]
+static CONTENT: &'static [u8] = b"hello hello hello hello hello hello hello xxhash";
+
+// Built by hand: `zstd --check --no-content-size` of `RAW_CONTENT`, which
+// stores it as a raw block, with the window descriptor at offset 5 changed
+// from 0x58 (2 MiB) to 0x00, the smallest window there is (1 KiB). That
+// still covers the 36-byte block, and the checksum is over the content
+// only, so the frame stays valid and pins the bottom of window decoding.
+#[cfg(test)]
+static RAW: [u8; 49] = [
+    0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x00, 0x21, 0x01, 0x00, 0x61, 0x62, 0x63,
+    0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f,
+    0x70, 0x71, 0x72, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x30,
+    0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x66, 0xb7, 0x09,
+    0x16,
+];
+
+#[cfg(test)]
+static RAW_CONTENT: &'static [u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+#[test]
+fn test_checksum() {
+    assert_eq!(checksum(CONTENT), 0xf545eb7c);
+
+    let mut state = ContentChecksum::new();
+    for chunk in RAW_CONTENT.chunks(5) {
+        state.update(chunk);
+    }
+    assert_eq!(state.finish(), 0x1609b766);
+}
+
+#[test]
+fn test_parse_frame() {
+    let info = parse_frame(&COMPRESSED).unwrap();
+    assert!(info.header.single_segment);
+    assert_eq!(info.header.content_size, Some(CONTENT.len() as u64));
+    assert_eq!(info.blocks, 1);
+    assert_eq!(info.checksum, Some((0xf545eb7c, 27)));
+    assert_eq!(info.frame_len, COMPRESSED.len());
+
+    let info = parse_frame(&RAW).unwrap();
+    assert_eq!(info.header.window_size, Some(1024));
+    assert_eq!(info.header.content_size, None);
+    let block = Blocks::new(&RAW, &info.header).next().unwrap().unwrap();
+    assert_eq!(block, Block { offset: 6, block_type: BlockType::Raw, size: 36, last: true });
+}
+
+#[test]
+fn test_verify_frame() {
+    assert!(verify_frame(&COMPRESSED, CONTENT).is_ok());
+    assert!(verify_frame(&RAW, RAW_CONTENT).is_ok());
+
+    let mut content = [0u8; 36];
+    for (d, s) in content.iter_mut().zip(RAW_CONTENT.iter()) {
+        *d = *s;
+    }
+    content[3] ^= 1;
+    assert_eq!(verify_frame(&RAW, &content).unwrap_err(),
+               Error { kind: ContentChecksum { stored: 0x1609b766, computed: checksum(&content) },
+                       offset: 45 });
+
+    assert_eq!(verify_frame(&COMPRESSED, RAW_CONTENT).unwrap_err(),
+               Error { kind: ContentSize { stored: 48, actual: 36 }, offset: 5 });
+    assert_eq!(parse_frame(RAW.slice_to(47)).unwrap_err(), Error { kind: Truncated, offset: 45 });
+    assert_eq!(parse_frame(RAW.slice_to(20)).unwrap_err(), Error { kind: Truncated, offset: 9 });
+}
@@ -0,0 +1,9 @@
+// Constants for the integration tests, written by `xxhash::codegen`;
+// `test_generated_is_current` in tests/literals.rs checks they haven't
+// gone stale.
+/// xxh64 of "Message::Ping", seed 0
+pub const PING: u64 = 0xa54f4f0e2f7ccda1;
+/// xxh64 of "Message::Pong", seed 0
+pub const PONG: u64 = 0x80ddd36ad6a16131;
+/// xxh32 of "Message::Close", seed 0
+pub const CLOSE32: u32 = 0x3f1b77e1;
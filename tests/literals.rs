@@ -0,0 +1,42 @@
+//! Constants generated by `xxhash::codegen` work where `xxh64!` can't:
+//! in a `static` and as `match` patterns.
+
+#![cfg(feature = "std")]
+#![allow(unstable)]
+
+#[macro_use] extern crate xxhash;
+
+use xxhash::codegen::{write_xxh64_consts, write_xxh32_consts};
+
+use common::{PING, PONG, CLOSE32};
+
+mod common;
+
+static IDS: [u64; 2] = [PING, PONG];
+
+fn name(id: u64) -> &'static str {
+    match id {
+        PING => "ping",
+        PONG => "pong",
+        _ => "unknown",
+    }
+}
+
+#[test]
+fn test_constants_match_macros() {
+    assert_eq!(IDS, [xxh64!("Message::Ping"), xxh64!("Message::Pong")]);
+    assert_eq!(CLOSE32, xxh32!("Message::Close"));
+
+    assert_eq!(name(xxh64!("Message::Ping")), "ping");
+    assert_eq!(name(xxh64!("Message::Pong")), "pong");
+    assert_eq!(name(xxh64!("Message::Close")), "unknown");
+}
+
+#[test]
+fn test_generated_is_current() {
+    let mut out = Vec::new();
+    write_xxh64_consts(&mut out, 0, &[("PING", "Message::Ping"), ("PONG", "Message::Pong")]).unwrap();
+    write_xxh32_consts(&mut out, 0, &[("CLOSE32", "Message::Close")]).unwrap();
+    let generated = String::from_utf8(out).unwrap();
+    assert!(include_str!("common/mod.rs").ends_with(generated.as_slice()));
+}